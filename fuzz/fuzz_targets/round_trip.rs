@@ -0,0 +1,27 @@
+#![no_main]
+
+use std::collections::BTreeSet;
+
+use libfuzzer_sys::fuzz_target;
+use nrbf::DeserializedRecord;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(rec) = nrbf::parse(data) {
+        let bytes = nrbf::serialize(&rec);
+        let reparsed = match nrbf::parse(&bytes) {
+            Ok(reparsed) => reparsed,
+            Err(err) => panic!("Can't parse the serialized document: {}", err),
+        };
+        assert_eq!(ids(&reparsed), ids(&rec));
+        assert!(reparsed.structurally_eq(&rec));
+    }
+});
+
+/// The root, header and record ids, which the serializer keeps
+fn ids(rec: &DeserializedRecord) -> (i32, i32, BTreeSet<i32>) {
+    (
+        rec.root_id,
+        rec.header_id,
+        rec.records.keys().copied().collect(),
+    )
+}
@@ -0,0 +1,173 @@
+use std::io::{self, Write};
+use std::ops::Range;
+
+use crate::dump::array_coords;
+use crate::records::*;
+
+/// The size of the stream header
+const HEADER_LEN: usize = 17;
+
+/// Print a hex dump of `bytes` with the records, class metadata and members that
+/// `rec.spans` locates in it. `rec` must have been parsed from `bytes` with span tracking;
+/// without spans, only the header and the rest of the bytes are told apart.
+///
+/// Every labelled part is indented below the part that contains it. Parts of up to
+/// 16 bytes without anything nested in them are printed on a single line.
+pub fn annotate<W: Write>(rec: &DeserializedRecord, bytes: &[u8], out: &mut W) -> io::Result<()> {
+    let mut nodes = Vec::new();
+    if let Some(spans) = &rec.spans {
+        for (&id, span) in &spans.records {
+            nodes.push(Node::new(span, 1, record_label(rec, id)));
+        }
+        for span in spans.class_metadata.values() {
+            nodes.push(Node::new(span, 2, "class metadata".into()));
+        }
+        for (&(parent, index), span) in &spans.members {
+            nodes.push(Node::new(span, 0, member_label(rec, parent, index)));
+        }
+    }
+    let header = 0..HEADER_LEN.min(bytes.len());
+    let end = nodes.iter().map(|n| n.span.end).max().unwrap_or(header.end);
+    nodes.push(Node::new(&header, 0, "header".into()));
+    if end < bytes.len() {
+        nodes.push(Node::new(&(end..bytes.len()), 0, "message end".into()));
+    }
+    // Outer parts first. A member and the record inlined into it can have the same span.
+    nodes.retain(|n| n.span.end <= bytes.len());
+    nodes.sort_by_key(|n| (n.span.start, std::cmp::Reverse(n.span.end), n.order));
+
+    let mut annotator = Annotator { bytes, out, pos: 0 };
+    let mut open: Vec<usize> = Vec::new();
+    for (i, node) in nodes.iter().enumerate() {
+        while let Some(&end) = open.last().filter(|&&end| end <= node.span.start) {
+            annotator.flush(end, open.len())?;
+            open.pop();
+        }
+        annotator.flush(node.span.start, open.len())?;
+
+        annotator.line(node.span.start, open.len(), &node.label)?;
+        let is_leaf = match nodes.get(i + 1) {
+            Some(next) => next.span.start >= node.span.end,
+            None => true,
+        };
+        if is_leaf && node.span.len() <= 16 {
+            writeln!(annotator.out, ": {}", hex(&bytes[node.span.clone()]))?;
+            annotator.pos = node.span.end;
+        } else {
+            writeln!(annotator.out, ":")?;
+            open.push(node.span.end);
+        }
+    }
+    while let Some(end) = open.pop() {
+        annotator.flush(end, open.len() + 1)?;
+    }
+    annotator.flush(bytes.len(), 0)
+}
+
+struct Node {
+    span: Range<usize>,
+    /// Which of several parts with the same span comes first
+    order: u8,
+    label: String,
+}
+
+impl Node {
+    fn new(span: &Range<usize>, order: u8, label: String) -> Self {
+        Self {
+            span: span.clone(),
+            order,
+            label,
+        }
+    }
+}
+
+struct Annotator<'a, W> {
+    bytes: &'a [u8],
+    out: &'a mut W,
+    /// Everything before this has been printed
+    pos: usize,
+}
+
+impl<W: Write> Annotator<'_, W> {
+    /// Print the bytes up to `end` that aren't part of anything nested, 16 per line.
+    fn flush(&mut self, end: usize, depth: usize) -> io::Result<()> {
+        while self.pos < end {
+            let line_end = end.min(self.pos + 16);
+            self.line(self.pos, depth, &hex(&self.bytes[self.pos..line_end]))?;
+            writeln!(self.out)?;
+            self.pos = line_end;
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, offset: usize, depth: usize, text: &str) -> io::Result<()> {
+        write!(
+            self.out,
+            "{:08x}  {:width$}{}",
+            offset,
+            "",
+            text,
+            width = depth * 2
+        )
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    hex.join(" ")
+}
+
+fn record_label(rec: &DeserializedRecord, id: i32) -> String {
+    let description = match rec.records.get(&id) {
+        Some(Record::BinaryLibrary(name)) => format!("library {:?}", name),
+        Some(Record::Class(class)) => rec.class_type(class).name.clone(),
+        Some(Record::ObjectArray(_)) => "object array".into(),
+        Some(Record::StringArray(_)) => "string array".into(),
+        Some(Record::BinaryArray(_)) => "array".into(),
+        Some(Record::PrimitiveArray(typ, _)) => format!("{:?} array", typ),
+        Some(Record::String(s)) if s.chars().count() > 32 => {
+            let start: String = s.chars().take(32).collect();
+            format!("string {:?}...", start)
+        }
+        Some(Record::String(s)) => format!("string {:?}", s),
+        None => return format!("#{}", id),
+    };
+    format!("#{} {}", id, description)
+}
+
+fn member_label(rec: &DeserializedRecord, parent: i32, index: usize) -> String {
+    match rec.records.get(&parent) {
+        Some(Record::Class(class)) => match rec.class_type(class).member_names.get(index) {
+            Some(name) => name.clone(),
+            None => format!("[{}]", index),
+        },
+        Some(Record::BinaryArray(array)) if array.rank() > 1 => {
+            format!("{:?}", array_coords(array, index))
+        }
+        _ => format!("[{}]", index),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::sample_stream;
+    use crate::parser::ParserOptions;
+
+    #[test]
+    fn annotate_sample() {
+        let bytes = sample_stream();
+        let options = ParserOptions {
+            track_spans: true,
+            ..Default::default()
+        };
+        let rec = options.parse(&bytes).unwrap();
+
+        let mut output = Vec::new();
+        annotate(&rec, &bytes, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("00000000  header:\n"));
+        assert!(output.contains("  #3 string \"save\": 06 03 00 00 00 04 73 61 76 65\n"));
+        assert!(output.ends_with(&format!("{:08x}  message end: 0b\n", bytes.len() - 1)));
+    }
+}
@@ -0,0 +1,138 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Class,
+    ClassUpgrade,
+    Item,
+    Trait,
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Category::Class => "class",
+            Category::ClassUpgrade => "class upgrade",
+            Category::Item => "item",
+            Category::Trait => "trait",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Upgrade {
+    pub id: &'static str,
+    pub name: &'static str,
+    pub category: Category,
+    /// Whether the upgrade may be marked as a starting upgrade
+    pub can_be_starting: bool,
+}
+
+/// Classes and their upgrades can't be starting upgrades, as in the original unlock loop.
+const fn upgrade(id: &'static str, name: &'static str, category: Category) -> Upgrade {
+    Upgrade {
+        id,
+        name,
+        category,
+        can_be_starting: !matches!(category, Category::Class | Category::ClassUpgrade),
+    }
+}
+
+/// The upgrades whose ids are known. The trait names are the in-game names noted next to
+/// the ids in the original unlock list, where "Heavy Weapons" was already marked as unsure.
+/// The other names are guesses spelled out from the ids, not confirmed in-game names.
+/// Fearless is missing since its id isn't known.
+pub const UPGRADES: &[Upgrade] = &[
+    upgrade("Hero_Class_Infantry", "Infantry", Category::Class),
+    upgrade("Hero_Class_Pikemen", "Pikemen", Category::Class),
+    upgrade("Hero_Class_Archers", "Archers", Category::Class),
+    upgrade(
+        "Hero_Upgrade_PikeCharge",
+        "Pike Charge",
+        Category::ClassUpgrade,
+    ),
+    upgrade(
+        "Hero_Upgrade_Plunge_Attack",
+        "Plunge Attack",
+        Category::ClassUpgrade,
+    ),
+    upgrade(
+        "Hero_Upgrade_ArcheryFocus",
+        "Archery Focus",
+        Category::ClassUpgrade,
+    ),
+    upgrade("Hero_Upgrade_Bomb", "Bomb", Category::Item),
+    upgrade("Hero_Upgrade_Horn", "Horn", Category::Item),
+    upgrade("Hero_Upgrade_Warhammer", "Warhammer", Category::Item),
+    upgrade("Hero_Upgrade_Mine", "Mine", Category::Item),
+    upgrade("Hero_Upgrade_Size", "Size", Category::Item),
+    upgrade("Hero_Upgrade_Grail", "Grail", Category::Item),
+    upgrade(
+        "Hero_Upgrade_PhilosophersStone",
+        "Philosophers Stone",
+        Category::Item,
+    ),
+    upgrade("Hero_Upgrade_Cornucopia", "Cornucopia", Category::Item),
+    upgrade("Hero_Trait_Sturdy", "Sure-Footed", Category::Trait),
+    upgrade("Hero_Trait_Fast", "Fleet of Foot", Category::Trait),
+    upgrade("Hero_Trait_CheaperSkills", "Skillful", Category::Trait),
+    upgrade("Hero_Trait_SharpWeapons", "Sharp Weapons", Category::Trait),
+    upgrade(
+        "Hero_Trait_FastReplenish",
+        "Rousing Speeches",
+        Category::Trait,
+    ),
+    upgrade("Hero_Trait_CheaperItems", "Collector", Category::Trait),
+    upgrade("Hero_Trait_ExtraArmor", "Ironskin", Category::Trait),
+    upgrade("Hero_Trait_ShortCooldown", "Energetic", Category::Trait),
+    upgrade("Hero_Trait_BluntWeapons", "Heavy Weapons", Category::Trait),
+    upgrade("Hero_Trait_ExtraUnit", "Popular", Category::Trait),
+    upgrade("Hero_Trait_ExtraUses", "Heavy Load", Category::Trait),
+    upgrade("Hero_Trait_Giant", "Mountain", Category::Trait),
+];
+
+/// Look up an upgrade by its internal id or its (case-insensitive) display name.
+pub fn find(name: &str) -> Option<&'static Upgrade> {
+    UPGRADES
+        .iter()
+        .find(|u| u.id == name || u.name.eq_ignore_ascii_case(name))
+}
+
+pub fn by_id(id: &str) -> Option<&'static Upgrade> {
+    UPGRADES.iter().find(|u| u.id == id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unique() {
+        for (i, a) in UPGRADES.iter().enumerate() {
+            for b in &UPGRADES[i + 1..] {
+                assert_ne!(a.id, b.id);
+                assert!(!a.name.eq_ignore_ascii_case(b.name), "{}", a.name);
+            }
+        }
+    }
+
+    #[test]
+    fn find_by_id_or_name() {
+        assert_eq!(find("Hero_Trait_ExtraArmor").unwrap().name, "Ironskin");
+        assert_eq!(find("ironskin").unwrap().id, "Hero_Trait_ExtraArmor");
+        assert_eq!(find("Heavy Weapons").unwrap().id, "Hero_Trait_BluntWeapons");
+        assert!(find("hero_trait_extraarmor").is_none());
+        assert!(find("Blunt Weapons").is_none());
+        assert!(by_id("Ironskin").is_none());
+    }
+
+    #[test]
+    fn starting_upgrades() {
+        for upgrade in UPGRADES {
+            let class = matches!(upgrade.category, Category::Class | Category::ClassUpgrade);
+            assert_eq!(upgrade.can_be_starting, !class, "{}", upgrade.id);
+        }
+        assert!(!by_id("Hero_Upgrade_PikeCharge").unwrap().can_be_starting);
+        assert!(by_id("Hero_Upgrade_Bomb").unwrap().can_be_starting);
+    }
+}
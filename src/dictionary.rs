@@ -0,0 +1,830 @@
+use std::fmt;
+
+use crate::path::{Location, Value, ValueMut};
+use crate::records::*;
+
+/// A `System.Collections.Generic.Dictionary<TKey, TValue>` as written by its
+/// `ISerializable` implementation: `Version`, `Comparer`, `HashSize` and, if it ever held
+/// any entries, a `KeyValuePairs` array of `KeyValuePair<TKey, TValue>` structs with `key`
+/// and `value` members. Get one with `DeserializedRecord::dictionary`.
+///
+/// Keys are looked up by value. String keys and values are references to `Record::String`s
+/// and are returned as `Value::Record`s.
+#[derive(Debug, Clone, Copy)]
+pub struct DictionaryView<'a> {
+    rec: &'a DeserializedRecord,
+    info: CollectionInfo,
+}
+
+/// A mutable `Dictionary<TKey, TValue>`. Changes bump `Version` and keep `HashSize` large
+/// enough for the .NET deserializer to read all entries.
+#[derive(Debug)]
+pub struct DictionaryViewMut<'a> {
+    rec: &'a mut DeserializedRecord,
+    info: CollectionInfo,
+}
+
+/// A `System.Collections.Generic.HashSet<T>` as written by its `ISerializable`
+/// implementation: `Version`, `Comparer`, `Capacity` and, unless it is empty, an
+/// `Elements` array. Get one with `DeserializedRecord::hash_set`.
+#[derive(Debug, Clone, Copy)]
+pub struct HashSetView<'a> {
+    rec: &'a DeserializedRecord,
+    info: CollectionInfo,
+}
+
+/// A mutable `HashSet<T>`. Changes bump `Version` and keep `Capacity` large enough.
+#[derive(Debug)]
+pub struct HashSetViewMut<'a> {
+    rec: &'a mut DeserializedRecord,
+    info: CollectionInfo,
+}
+
+/// The members of a serialized dictionary or hash set.
+#[derive(Debug, Clone, Copy)]
+struct CollectionInfo {
+    id: i32,
+    version_index: usize,
+    /// Index of `HashSize` or `Capacity`
+    size_index: usize,
+    /// Index of `KeyValuePairs` or `Elements`, if present
+    array_index: Option<usize>,
+    /// The array itself, `None` if the member is missing or null
+    array_id: Option<i32>,
+}
+
+/// A key, value or element that doesn't fit the type the collection stores.
+#[derive(Debug)]
+pub struct TypeMismatch {
+    pub value: Member,
+    pub expected: MemberType,
+}
+
+impl fmt::Display for TypeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} doesn't fit {:?}", self.value, self.expected)
+    }
+}
+
+impl std::error::Error for TypeMismatch {}
+
+const KEY_VALUE_PAIRS: &str = "KeyValuePairs";
+const ELEMENTS: &str = "Elements";
+
+impl DeserializedRecord {
+    /// View the record with the given id as a dictionary. Returns `None` if it doesn't
+    /// have the members of a serialized `Dictionary<TKey, TValue>`, or if the keys or
+    /// values of its entries don't fit their member types.
+    pub fn dictionary(&self, id: i32) -> Option<DictionaryView<'_>> {
+        Some(DictionaryView {
+            info: dictionary_info(self, id)?,
+            rec: self,
+        })
+    }
+
+    pub fn dictionary_mut(&mut self, id: i32) -> Option<DictionaryViewMut<'_>> {
+        Some(DictionaryViewMut {
+            info: dictionary_info(self, id)?,
+            rec: self,
+        })
+    }
+
+    /// View the record with the given id as a hash set. Returns `None` if it doesn't have
+    /// the members of a serialized `HashSet<T>`.
+    pub fn hash_set(&self, id: i32) -> Option<HashSetView<'_>> {
+        Some(HashSetView {
+            info: collection_info(self, id, "Capacity", ELEMENTS)?,
+            rec: self,
+        })
+    }
+
+    pub fn hash_set_mut(&mut self, id: i32) -> Option<HashSetViewMut<'_>> {
+        Some(HashSetViewMut {
+            info: collection_info(self, id, "Capacity", ELEMENTS)?,
+            rec: self,
+        })
+    }
+}
+
+fn collection_info(
+    rec: &DeserializedRecord,
+    id: i32,
+    size_name: &str,
+    array_name: &str,
+) -> Option<CollectionInfo> {
+    let class = match rec.records.get(&id)? {
+        Record::Class(class) => class,
+        _ => return None,
+    };
+    let class_type = rec.class_type(class);
+    let index = |name| class_type.member_names.iter().position(|n| n == name);
+    let int_index = |name| {
+        let index = index(name)?;
+        match class.members.get(index)? {
+            Member::Primitive(Primitive::Int32(_)) => Some(index),
+            _ => None,
+        }
+    };
+
+    let array_index = index(array_name);
+    let array_id = match array_index.map(|i| class.members.get(i)) {
+        None | Some(Some(Member::Null)) => None,
+        Some(Some(Member::Reference(array_id))) => match rec.records.get(array_id)? {
+            Record::ObjectArray(_) | Record::StringArray(_) | Record::PrimitiveArray(..) => {
+                Some(*array_id)
+            }
+            Record::BinaryArray(array) if array.rank() == 1 => Some(*array_id),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(CollectionInfo {
+        id,
+        version_index: int_index("Version")?,
+        size_index: int_index(size_name)?,
+        array_index,
+        array_id,
+    })
+}
+
+fn dictionary_info(rec: &DeserializedRecord, id: i32) -> Option<CollectionInfo> {
+    let info = collection_info(rec, id, "HashSize", KEY_VALUE_PAIRS)?;
+    // Every entry has to be a KeyValuePair
+    for member in array_members(rec, info.array_id) {
+        let class = match member {
+            Member::Reference(id) => match rec.records.get(id)? {
+                Record::Class(class) => class,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let class_type = rec.class_type(class);
+        for name in &["key", "value"] {
+            let index = class_type.member_names.iter().position(|n| n == name)?;
+            if !fits(&class_type.member_types[index], class.members.get(index)?) {
+                return None;
+            }
+        }
+    }
+    Some(info)
+}
+
+impl<'a> DictionaryView<'a> {
+    pub fn len(&self) -> usize {
+        array_members(self.rec, self.info.array_id).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The entries in the order they were serialized.
+    pub fn iter(&self) -> impl Iterator<Item = (Value<'a>, Value<'a>)> + 'a {
+        let rec = self.rec;
+        self.pairs().map(move |pair| {
+            (
+                deref(rec, rec.class_member(pair, "key")),
+                deref(rec, rec.class_member(pair, "value")),
+            )
+        })
+    }
+
+    pub fn get(&self, key: &Primitive) -> Option<Value<'a>> {
+        let rec = self.rec;
+        self.pairs()
+            .find(|pair| matches_key(rec, rec.class_member(pair, "key"), key))
+            .map(|pair| deref(rec, rec.class_member(pair, "value")))
+    }
+
+    pub fn contains_key(&self, key: &Primitive) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn pairs(&self) -> impl Iterator<Item = &'a Class> + 'a {
+        let rec = self.rec;
+        array_members(rec, self.info.array_id)
+            .iter()
+            .map(move |member| rec.records[member.as_reference()].as_class())
+    }
+
+    /// The index in the array and the id of the pair with the given key.
+    fn find(&self, key: &Primitive) -> Option<(usize, i32)> {
+        array_members(self.rec, self.info.array_id)
+            .iter()
+            .enumerate()
+            .map(|(i, member)| (i, *member.as_reference()))
+            .find(|(_, id)| {
+                let pair = self.rec.records[id].as_class();
+                matches_key(self.rec, self.rec.class_member(pair, "key"), key)
+            })
+    }
+}
+
+impl DictionaryViewMut<'_> {
+    pub fn len(&self) -> usize {
+        self.as_view().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.as_view().is_empty()
+    }
+
+    pub fn as_view(&self) -> DictionaryView<'_> {
+        DictionaryView {
+            rec: self.rec,
+            info: self.info,
+        }
+    }
+
+    pub fn get(&self, key: &Primitive) -> Option<Value<'_>> {
+        self.as_view().get(key)
+    }
+
+    /// Get the value for a key. References are followed, so a string value can be edited
+    /// in place through `ValueMut::Record`.
+    pub fn get_mut(&mut self, key: &Primitive) -> Option<ValueMut<'_>> {
+        let (_, pair_id) = self.as_view().find(key)?;
+        let pair = self.rec.records[&pair_id].as_class();
+        let value_index = self.rec.class_member_index(pair, "value");
+        self.rec.get_mut(Location::Member(pair_id, value_index))
+    }
+
+    /// Set the value for a key and return the old value, if there was one.
+    /// A string key is stored as a new string record.
+    ///
+    /// New entries are appended as `KeyValuePair` structs, copying the class of the existing
+    /// entries. Nothing is changed if `key` or `value` don't fit its member types.
+    pub fn insert(
+        &mut self,
+        key: Primitive,
+        value: Member,
+    ) -> Result<Option<Member>, TypeMismatch> {
+        if let Some((_, pair_id)) = self.as_view().find(&key) {
+            let pair = self.rec.records[&pair_id].as_class();
+            let value_index = self.rec.class_member_index(pair, "value");
+            let value = check_member_type(self.rec.class_type(pair), value_index, value)?;
+            let pair = self.rec.records.get_mut(&pair_id).unwrap().as_class_mut();
+            let old = std::mem::replace(&mut pair.members[value_index], value);
+            bump_version(self.rec, self.info);
+            return Ok(Some(old));
+        }
+
+        let pair_name = self.pair_name();
+        let class_type_id = self.pair_class_type(&pair_name, &key, &value);
+        let class_type = &self.rec.class_types[class_type_id];
+        let index = |name| {
+            class_type
+                .member_names
+                .iter()
+                .position(|n| n == name)
+                .unwrap()
+        };
+        let (key_index, value_index) = (index("key"), index("value"));
+        let key_type = &class_type.member_types[key_index];
+        if !key_fits(key_type, &key) {
+            return Err(TypeMismatch {
+                value: Member::Primitive(key),
+                expected: key_type.clone(),
+            });
+        }
+        let value = check_member_type(class_type, value_index, value)?;
+        let mut members = vec![Member::Null; class_type.member_names.len()];
+
+        let (key, key_record) = match key {
+            Primitive::String(s) => {
+                let id = self.rec.add_record(Record::String(s));
+                (Member::Reference(id), Some(id))
+            }
+            key => (Member::Primitive(key), None),
+        };
+
+        let array_id = ensure_array(
+            self.rec,
+            &mut self.info,
+            KEY_VALUE_PAIRS,
+            || MemberType::SystemClass(format!("{}[]", pair_name)),
+            || {
+                Record::BinaryArray(BinaryArray {
+                    array_type: BinaryArrayType::Single,
+                    lengths: vec![0],
+                    lower_bounds: Vec::new(),
+                    member_type: MemberType::SystemClass(pair_name.clone()),
+                    values: Vec::new(),
+                })
+            },
+        );
+        members[key_index] = key;
+        members[value_index] = value;
+
+        let pair = Class {
+            class_type_id,
+            members,
+        };
+        let pair_id = self.rec.add_record(Record::Class(pair));
+        if let Some(key_record) = key_record {
+            self.rec.reinline(pair_id, &[key_record]);
+        }
+
+        // KeyValuePairs are structs, which are always written inline
+        push_element(self.rec, array_id, Member::Reference(pair_id))?;
+        self.rec.reinline(array_id, &[pair_id]);
+        update_size(self.rec, self.info);
+        bump_version(self.rec, self.info);
+        Ok(None)
+    }
+
+    /// Remove an entry and return its value. The key and value records stay in the
+    /// document until unreachable records are removed.
+    pub fn remove(&mut self, key: &Primitive) -> Option<Member> {
+        let (index, pair_id) = self.as_view().find(key)?;
+        remove_element(self.rec, self.info.array_id.unwrap(), index);
+        bump_version(self.rec, self.info);
+
+        let pair = self.rec.records[&pair_id].as_class();
+        Some(self.rec.class_member(pair, "value").clone())
+    }
+
+    /// `KeyValuePair<TKey, TValue>` with the type arguments of the dictionary.
+    fn pair_name(&self) -> String {
+        if let Some(array_id) = self.info.array_id {
+            if let Record::BinaryArray(array) = &self.rec.records[&array_id] {
+                if let MemberType::SystemClass(name) | MemberType::Class(name, _) =
+                    &array.member_type
+                {
+                    return name.clone();
+                }
+            }
+        }
+        let class = self.rec.records[&self.info.id].as_class();
+        let name = &self.rec.class_type(class).name;
+        let args = name.find('`').map_or("", |i| &name[i..]);
+        format!("System.Collections.Generic.KeyValuePair{}", args)
+    }
+
+    /// The id of the `KeyValuePair` class type, taken from an existing entry or created.
+    fn pair_class_type(&mut self, pair_name: &str, key: &Primitive, value: &Member) -> usize {
+        let has_member = |t: &ClassType, name| t.member_names.iter().any(|n| n == name);
+        let existing = match array_members(self.rec, self.info.array_id).first() {
+            Some(member) => Some(
+                self.rec.records[member.as_reference()]
+                    .as_class()
+                    .class_type_id,
+            ),
+            None => self.rec.class_types.iter().position(|t| {
+                t.name == pair_name && has_member(t, "key") && has_member(t, "value")
+            }),
+        };
+        if let Some(class_type_id) = existing {
+            return class_type_id;
+        }
+
+        self.rec.class_types.push(ClassType {
+            name: pair_name.into(),
+            library_id: 0,
+            system_class: true,
+            has_member_types: true,
+            member_names: vec!["key".into(), "value".into()],
+            member_types: vec![
+                match key {
+                    Primitive::String(_) => MemberType::String,
+                    key => MemberType::Primitive(key.primitive_type()),
+                },
+                member_type_of(self.rec, value),
+            ],
+        });
+        self.rec.class_types.len() - 1
+    }
+}
+
+impl<'a> HashSetView<'a> {
+    pub fn len(&self) -> usize {
+        match self.info.array_id.map(|id| &self.rec.records[&id]) {
+            Some(Record::PrimitiveArray(_, vals)) => vals.len(),
+            _ => array_members(self.rec, self.info.array_id).len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The elements in the order they were serialized.
+    pub fn iter(&self) -> impl Iterator<Item = Value<'a>> + 'a {
+        let rec = self.rec;
+        let (members, primitives): (&[Member], &[Primitive]) =
+            match self.info.array_id.map(|id| &rec.records[&id]) {
+                Some(Record::PrimitiveArray(_, vals)) => (&[], vals),
+                _ => (array_members(rec, self.info.array_id), &[]),
+            };
+        members
+            .iter()
+            .map(move |member| deref(rec, member))
+            .chain(primitives.iter().map(Value::Primitive))
+    }
+
+    pub fn contains(&self, val: &Primitive) -> bool {
+        self.position(val).is_some()
+    }
+
+    fn position(&self, val: &Primitive) -> Option<usize> {
+        match self.info.array_id.map(|id| &self.rec.records[&id]) {
+            Some(Record::PrimitiveArray(_, vals)) => vals.iter().position(|v| v == val),
+            _ => array_members(self.rec, self.info.array_id)
+                .iter()
+                .position(|member| matches_key(self.rec, member, val)),
+        }
+    }
+}
+
+impl HashSetViewMut<'_> {
+    pub fn len(&self) -> usize {
+        self.as_view().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.as_view().is_empty()
+    }
+
+    pub fn as_view(&self) -> HashSetView<'_> {
+        HashSetView {
+            rec: self.rec,
+            info: self.info,
+        }
+    }
+
+    pub fn contains(&self, val: &Primitive) -> bool {
+        self.as_view().contains(val)
+    }
+
+    /// Add an element. Returns `false` if it was already present.
+    ///
+    /// Strings are stored as new string records. Nothing is changed if the set holds
+    /// elements of a different type.
+    pub fn insert(&mut self, val: Primitive) -> Result<bool, TypeMismatch> {
+        if self.contains(&val) {
+            return Ok(false);
+        }
+        if let Some(array_id) = self.info.array_id {
+            let expected = element_type(&self.rec.records[&array_id]);
+            if !key_fits(&expected, &val) {
+                return Err(TypeMismatch {
+                    value: Member::Primitive(val),
+                    expected,
+                });
+            }
+        }
+
+        let array_id = match &val {
+            Primitive::String(_) => ensure_array(
+                self.rec,
+                &mut self.info,
+                ELEMENTS,
+                || MemberType::StringArray,
+                || Record::StringArray(Vec::new()),
+            ),
+            val => {
+                let typ = val.primitive_type();
+                ensure_array(
+                    self.rec,
+                    &mut self.info,
+                    ELEMENTS,
+                    || MemberType::PrimitiveArray(typ.clone()),
+                    || Record::PrimitiveArray(typ.clone(), Vec::new()),
+                )
+            }
+        };
+
+        match val {
+            Primitive::String(s) => {
+                let id = self.rec.add_record(Record::String(s));
+                push_element(self.rec, array_id, Member::Reference(id))?;
+                // Strings in arrays are written inline the first time they occur
+                self.rec.reinline(array_id, &[id]);
+            }
+            val => push_element(self.rec, array_id, Member::Primitive(val))?,
+        }
+        update_size(self.rec, self.info);
+        bump_version(self.rec, self.info);
+        Ok(true)
+    }
+
+    /// Remove an element. Returns `false` if it wasn't present.
+    pub fn remove(&mut self, val: &Primitive) -> bool {
+        let index = match self.as_view().position(val) {
+            Some(index) => index,
+            None => return false,
+        };
+        remove_element(self.rec, self.info.array_id.unwrap(), index);
+        bump_version(self.rec, self.info);
+        true
+    }
+}
+
+/// The elements of an array of members, or nothing if there is no array.
+fn array_members(rec: &DeserializedRecord, array_id: Option<i32>) -> &[Member] {
+    match array_id.map(|id| &rec.records[&id]) {
+        Some(Record::ObjectArray(vals)) | Some(Record::StringArray(vals)) => vals,
+        Some(Record::BinaryArray(array)) => &array.values,
+        _ => &[],
+    }
+}
+
+fn deref<'a>(rec: &'a DeserializedRecord, member: &'a Member) -> Value<'a> {
+    match member {
+        Member::Reference(id) => match rec.records.get(id) {
+            Some(record) => Value::Record(record),
+            None => Value::Member(member),
+        },
+        _ => Value::Member(member),
+    }
+}
+
+/// Whether a key or element is equal to `key`. Strings match string records.
+fn matches_key(rec: &DeserializedRecord, member: &Member, key: &Primitive) -> bool {
+    match (member, key) {
+        (Member::Primitive(val), key) => val == key,
+        (Member::Reference(id), Primitive::String(key)) => {
+            matches!(rec.records.get(id), Some(Record::String(s)) if s == key)
+        }
+        _ => false,
+    }
+}
+
+fn member_type_of(rec: &DeserializedRecord, member: &Member) -> MemberType {
+    match member {
+        Member::Primitive(val) => MemberType::Primitive(val.primitive_type()),
+        Member::Reference(id) if matches!(rec.records.get(id), Some(Record::String(_))) => {
+            MemberType::String
+        }
+        _ => MemberType::Object,
+    }
+}
+
+/// Whether a member can be stored where a member of the type `expected` is declared.
+/// Whether a member can be stored where `expected` is declared. Only `Object` members
+/// hold boxed primitives, all others hold references or nulls.
+pub(crate) fn fits(expected: &MemberType, member: &Member) -> bool {
+    match expected {
+        MemberType::Primitive(typ) => {
+            matches!(member, Member::Primitive(val) if val.primitive_type() == *typ)
+        }
+        MemberType::Object => true,
+        _ => !matches!(member, Member::Primitive(_)),
+    }
+}
+
+/// Like `fits` for a key or element, which is stored as a string record if it's a string.
+fn key_fits(expected: &MemberType, key: &Primitive) -> bool {
+    match key {
+        Primitive::String(_) => !matches!(expected, MemberType::Primitive(_)),
+        key => fits(expected, &Member::Primitive(key.clone())),
+    }
+}
+
+fn check_member_type(
+    class_type: &ClassType,
+    index: usize,
+    member: Member,
+) -> Result<Member, TypeMismatch> {
+    let expected = &class_type.member_types[index];
+    if fits(expected, &member) {
+        Ok(member)
+    } else {
+        Err(TypeMismatch {
+            value: member,
+            expected: expected.clone(),
+        })
+    }
+}
+
+/// The type of the elements of an array record.
+pub(crate) fn element_type(array: &Record) -> MemberType {
+    match array {
+        Record::StringArray(_) => MemberType::String,
+        Record::PrimitiveArray(typ, _) => MemberType::Primitive(typ.clone()),
+        Record::BinaryArray(array) => array.member_type.clone(),
+        _ => MemberType::Object,
+    }
+}
+
+/// The id of the array of a collection, adding the array and if necessary its member
+/// (which changes the class type of the collection) if the collection never had one.
+fn ensure_array(
+    rec: &mut DeserializedRecord,
+    info: &mut CollectionInfo,
+    name: &str,
+    member_type: impl FnOnce() -> MemberType,
+    array: impl FnOnce() -> Record,
+) -> i32 {
+    if let Some(array_id) = info.array_id {
+        return array_id;
+    }
+
+    let array_id = rec.add_record(array());
+    let class = rec.records[&info.id].as_class();
+    let index = match info.array_index {
+        Some(index) => index,
+        None => {
+            let mut class_type = rec.class_type(class).clone();
+            class_type.member_names.push(name.into());
+            class_type.member_types.push(member_type());
+            rec.class_types.push(class_type);
+            let class_type_id = rec.class_types.len() - 1;
+            let class = rec.records.get_mut(&info.id).unwrap().as_class_mut();
+            class.class_type_id = class_type_id;
+            class.members.push(Member::Null);
+            class.members.len() - 1
+        }
+    };
+
+    let class = rec.records.get_mut(&info.id).unwrap().as_class_mut();
+    class.members[index] = Member::Reference(array_id);
+    info.array_index = Some(index);
+    info.array_id = Some(array_id);
+    array_id
+}
+
+fn push_element(
+    rec: &mut DeserializedRecord,
+    array_id: i32,
+    val: Member,
+) -> Result<(), TypeMismatch> {
+    let array = rec.records.get_mut(&array_id).unwrap();
+    let expected = element_type(array);
+    if !fits(&expected, &val) {
+        return Err(TypeMismatch {
+            value: val,
+            expected,
+        });
+    }
+    match (array, val) {
+        (Record::ObjectArray(vals), val) | (Record::StringArray(vals), val) => vals.push(val),
+        (Record::BinaryArray(array), val) => array
+            .push(val)
+            .expect("collection_info checked the rank of the array"),
+        (Record::PrimitiveArray(_, vals), Member::Primitive(val)) => vals.push(val),
+        _ => unreachable!("collection_info checked the type of the array"),
+    }
+    Ok(())
+}
+
+fn remove_element(rec: &mut DeserializedRecord, array_id: i32, index: usize) {
+    match rec.records.get_mut(&array_id).unwrap() {
+        Record::ObjectArray(vals) | Record::StringArray(vals) => {
+            vals.remove(index);
+        }
+        Record::BinaryArray(array) => {
+            array.values.remove(index);
+            array.lengths[0] -= 1;
+        }
+        Record::PrimitiveArray(_, vals) => {
+            vals.remove(index);
+        }
+        _ => unreachable!("collection_info checked the type of the array"),
+    }
+    rec.reinline(array_id, &[]);
+}
+
+fn bump_version(rec: &mut DeserializedRecord, info: CollectionInfo) {
+    let class = rec.records.get_mut(&info.id).unwrap().as_class_mut();
+    if let Member::Primitive(Primitive::Int32(version)) = &mut class.members[info.version_index] {
+        *version = version.wrapping_add(1);
+    }
+}
+
+/// The .NET deserializer only reads the entries if the hash size (the number of buckets)
+/// isn't 0, and it has to grow its buckets if there are more entries than that.
+/// Use a prime at least as large as the number of entries, like .NET does.
+fn update_size(rec: &mut DeserializedRecord, info: CollectionInfo) {
+    let len = match info.array_id.map(|id| &rec.records[&id]) {
+        Some(Record::PrimitiveArray(_, vals)) => vals.len(),
+        _ => array_members(rec, info.array_id).len(),
+    } as i32;
+    let class = rec.records.get_mut(&info.id).unwrap().as_class_mut();
+    if let Member::Primitive(Primitive::Int32(size)) = &mut class.members[info.size_index] {
+        if *size < len {
+            *size = (len.max(3)..).find(|&n| is_prime(n)).unwrap();
+        }
+    }
+}
+
+fn is_prime(n: i32) -> bool {
+    n >= 2 && (2..).take_while(|d| d * d <= n).all(|d| n % d != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{collections, int, PAIR};
+    use crate::{parse, serialize};
+
+    fn entries(rec: &DeserializedRecord, id: i32) -> Vec<String> {
+        rec.dictionary(id)
+            .unwrap()
+            .iter()
+            .map(|(k, v)| match (k, v) {
+                (Value::Record(Record::String(k)), Value::Member(Member::Primitive(v))) => {
+                    format!("{}={}", k, v)
+                }
+                _ => panic!("Unexpected entry"),
+            })
+            .collect()
+    }
+
+    fn int_member(rec: &DeserializedRecord, id: i32, name: &str) -> i32 {
+        rec.class_member(rec.records[&id].as_class(), name).as_i32()
+    }
+
+    #[test]
+    fn dictionary() {
+        let mut rec = collections();
+        let gold = Primitive::String("gold".into());
+        assert!(matches!(
+            rec.dictionary(2).unwrap().get(&gold),
+            Some(Value::Member(Member::Primitive(Primitive::Int32(5))))
+        ));
+        assert!(rec.dictionary(1).is_none());
+
+        let mut dict = rec.dictionary_mut(2).unwrap();
+        assert!(matches!(
+            dict.insert(gold.clone(), int(6)),
+            Ok(Some(Member::Primitive(Primitive::Int32(5))))
+        ));
+        for (i, key) in ["wood", "stone", "iron"].iter().enumerate() {
+            assert!(dict
+                .insert(Primitive::String(key.to_string()), int(i as i32))
+                .unwrap()
+                .is_none());
+        }
+        assert!(dict.remove(&Primitive::String("wood".into())).is_some());
+        assert!(dict.remove(&Primitive::String("wood".into())).is_none());
+        assert_eq!(dict.len(), 3);
+        assert_eq!(int_member(&rec, 2, "Version"), 8);
+        assert_eq!(int_member(&rec, 2, "HashSize"), 5);
+
+        let mut empty = rec.dictionary_mut(5).unwrap();
+        assert!(empty.is_empty());
+        empty.insert(Primitive::String("x".into()), int(1)).unwrap();
+        assert_eq!(int_member(&rec, 5, "HashSize"), 3);
+        // The new entry reuses the class of the other dictionary's entries
+        assert_eq!(rec.class_types.iter().filter(|t| t.name == PAIR).count(), 1);
+
+        let rec = parse(&serialize(&rec)).unwrap();
+        assert_eq!(entries(&rec, 2), ["gold=6", "stone=1", "iron=2"]);
+        assert_eq!(entries(&rec, 5), ["x=1"]);
+    }
+
+    #[test]
+    fn dictionary_wrong_types() {
+        let mut rec = collections();
+        let before = serialize(&rec);
+        let mut dict = rec.dictionary_mut(2).unwrap();
+        let boolean = Member::Primitive(Primitive::Boolean(true));
+        for key in &["gold", "new"] {
+            let err = dict
+                .insert(Primitive::String(key.to_string()), boolean.clone())
+                .unwrap_err();
+            assert!(matches!(
+                err.expected,
+                MemberType::Primitive(PrimitiveType::Int32)
+            ));
+        }
+        let err = dict.insert(Primitive::Int32(1), int(1)).unwrap_err();
+        assert!(matches!(err.expected, MemberType::String));
+        assert_eq!(serialize(&rec), before);
+
+        // Entries that don't fit their own class aren't a dictionary
+        rec.records.get_mut(&4).unwrap().as_class_mut().members[1] = boolean;
+        assert!(rec.dictionary(2).is_none());
+        assert!(rec.dictionary_mut(2).is_none());
+    }
+
+    #[test]
+    fn hash_set() {
+        let mut rec = collections();
+        let mut set = rec.hash_set_mut(6).unwrap();
+        for s in &["a", "b", "c"] {
+            assert!(set.insert(Primitive::String(s.to_string())).unwrap());
+        }
+        assert!(!set.insert(Primitive::String("a".into())).unwrap());
+        let err = set.insert(Primitive::Int32(1)).unwrap_err();
+        assert!(matches!(err.expected, MemberType::String));
+        assert_eq!(set.len(), 3);
+        assert!(set.remove(&Primitive::String("b".into())));
+        assert!(!set.contains(&Primitive::String("b".into())));
+        assert_eq!(int_member(&rec, 6, "Capacity"), 3);
+
+        let rec = parse(&serialize(&rec)).unwrap();
+        let set = rec.hash_set(6).unwrap();
+        let elements: Vec<&str> = set
+            .iter()
+            .map(|v| match v {
+                Value::Record(record) => record.as_string(),
+                _ => panic!("Unexpected element"),
+            })
+            .collect();
+        assert_eq!(elements, ["a", "c"]);
+        assert!(set.contains(&Primitive::String("c".into())));
+    }
+}
@@ -0,0 +1,454 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::dump::array_coords;
+use crate::list::ListView;
+use crate::path::{Path, Segment, Value};
+use crate::records::*;
+
+/// A value that differs between two documents, see `DeserializedRecord::diff`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub path: Path,
+    /// A description of the old value, or `None` if the value was added
+    pub old: Option<String>,
+    /// A description of the new value, or `None` if the value was removed
+    pub new: Option<String>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.segments.is_empty() {
+            write!(f, "<root>: ")?;
+        } else {
+            write!(f, "{}: ", self.path)?;
+        }
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => write!(f, "{} -> {}", old, new),
+            (None, Some(new)) => write!(f, "added {}", new),
+            (Some(old), None) => write!(f, "removed {}", old),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl DeserializedRecord {
+    /// Whether both documents contain the same object graph, starting from their roots.
+    /// Record ids, class type ids, the layout and unreachable records don't matter,
+    /// but which records are shared does. Floats are compared by their bits.
+    pub fn structurally_eq(&self, other: &DeserializedRecord) -> bool {
+        let differ = Differ::run(self, other);
+        differ.changes.is_empty() && !differ.shared_differently
+    }
+
+    /// List the values that differ between this document and `other`, walking both
+    /// from their roots. Members are matched by name and array elements by index.
+    /// A `List<T>` is compared by its items up to `_size`, like paths index it.
+    pub fn diff(&self, other: &DeserializedRecord) -> Vec<Change> {
+        Differ::run(self, other).changes
+    }
+}
+
+enum Item<'a> {
+    Records(Path, i32, i32),
+    Members(Path, &'a Member, &'a Member),
+    Primitives(Path, &'a Primitive, &'a Primitive),
+    Change(Change),
+}
+
+struct Differ<'a> {
+    old: &'a DeserializedRecord,
+    new: &'a DeserializedRecord,
+    /// Items are processed from the back, so that changes come out in depth-first order
+    stack: Vec<Item<'a>>,
+    seen: HashSet<(i32, i32)>,
+    forward: HashMap<i32, i32>,
+    backward: HashMap<i32, i32>,
+    /// Whether a record of one document was paired with two different records of the other
+    shared_differently: bool,
+    changes: Vec<Change>,
+}
+
+impl<'a> Differ<'a> {
+    fn run(old: &'a DeserializedRecord, new: &'a DeserializedRecord) -> Self {
+        let mut differ = Differ {
+            old,
+            new,
+            stack: vec![Item::Records(Path::default(), old.root_id, new.root_id)],
+            seen: HashSet::new(),
+            forward: HashMap::new(),
+            backward: HashMap::new(),
+            shared_differently: false,
+            changes: Vec::new(),
+        };
+        while let Some(item) = differ.stack.pop() {
+            match item {
+                Item::Records(path, old_id, new_id) => differ.records(path, old_id, new_id),
+                Item::Members(path, old, new) => differ.members(path, old, new),
+                Item::Primitives(path, old, new) => {
+                    if !same_primitive(old, new) {
+                        differ.changed(path, describe_primitives(old, new));
+                    }
+                }
+                Item::Change(change) => differ.changes.push(change),
+            }
+        }
+        differ
+    }
+
+    fn changed(&mut self, path: Path, (old, new): (String, String)) {
+        self.changes.push(Change {
+            path,
+            old: Some(old),
+            new: Some(new),
+        });
+    }
+
+    fn members(&mut self, path: Path, old: &'a Member, new: &'a Member) {
+        match (old, new) {
+            (Member::Reference(old_id), Member::Reference(new_id)) => {
+                self.records(path, *old_id, *new_id)
+            }
+            (Member::Primitive(old), Member::Primitive(new)) => {
+                if !same_primitive(old, new) {
+                    self.changed(path, describe_primitives(old, new));
+                }
+            }
+            (Member::Null, Member::Null)
+            | (Member::Null, Member::NullMultiple(_))
+            | (Member::NullMultiple(_), Member::Null)
+            | (Member::NullMultiple(_), Member::NullMultiple(_)) => (),
+            _ => {
+                let change = (describe(self.old, old), describe(self.new, new));
+                self.changed(path, change);
+            }
+        }
+    }
+
+    fn records(&mut self, path: Path, old_id: i32, new_id: i32) {
+        if !self.seen.insert((old_id, new_id)) {
+            return;
+        }
+        let forward = *self.forward.entry(old_id).or_insert(new_id);
+        let backward = *self.backward.entry(new_id).or_insert(old_id);
+        if forward != new_id || backward != old_id {
+            self.shared_differently = true;
+        }
+
+        let (old, new) = match (self.old.records.get(&old_id), self.new.records.get(&new_id)) {
+            (Some(old), Some(new)) => (old, new),
+            (None, None) => return,
+            _ => {
+                let change = (
+                    describe_record(self.old, old_id),
+                    describe_record(self.new, new_id),
+                );
+                return self.changed(path, change);
+            }
+        };
+
+        let mut children = Vec::new();
+        match (old, new) {
+            (Record::Class(old_class), Record::Class(new_class))
+                if self.same_class_type(old_class, new_class) =>
+            {
+                // Lists are compared up to `_size`, so that the capacity of `_items` doesn't
+                // matter
+                let (old_list, new_list) = (self.old.list(old_id), self.new.list(new_id));
+                let is_list = old_list.is_some() && new_list.is_some();
+                if let (Some(old_list), Some(new_list)) = (old_list, new_list) {
+                    self.list_items(&path, old_list, new_list, &mut children);
+                }
+                let skip = |name: &String| is_list && (name == "_items" || name == "_size");
+
+                let old_names = &self.old.class_type(old_class).member_names;
+                let new_names = &self.new.class_type(new_class).member_names;
+                let member = |name: &String| {
+                    let mut path = path.clone();
+                    path.push(Segment::Member(name.clone()));
+                    path
+                };
+                for (name, old_member) in old_names.iter().zip(&old_class.members) {
+                    if skip(name) {
+                        continue;
+                    }
+                    match new_names.iter().position(|n| n == name) {
+                        Some(i) => match new_class.members.get(i) {
+                            Some(new_member) => {
+                                children.push(Item::Members(member(name), old_member, new_member))
+                            }
+                            None => children.push(self.removed(member(name), old_member)),
+                        },
+                        None => children.push(self.removed(member(name), old_member)),
+                    }
+                }
+                for (name, new_member) in new_names.iter().zip(&new_class.members) {
+                    if !old_names.contains(name) && !skip(name) {
+                        children.push(self.added(member(name), new_member));
+                    }
+                }
+            }
+            (Record::ObjectArray(old_vals), Record::ObjectArray(new_vals))
+            | (Record::StringArray(old_vals), Record::StringArray(new_vals)) => {
+                self.elements(&path, old_vals, new_vals, |i| vec![i as i32], &mut children);
+            }
+            (Record::BinaryArray(old_array), Record::BinaryArray(new_array))
+                if old_array.lengths == new_array.lengths
+                    && old_array.lower_bounds == new_array.lower_bounds =>
+            {
+                let coords = |i| array_coords(old_array, i);
+                self.elements(
+                    &path,
+                    &old_array.values,
+                    &new_array.values,
+                    coords,
+                    &mut children,
+                );
+            }
+            (Record::BinaryArray(old_array), Record::BinaryArray(new_array))
+                if is_vector(old_array) && is_vector(new_array) =>
+            {
+                let (old_vals, new_vals) = (&old_array.values, &new_array.values);
+                self.elements(&path, old_vals, new_vals, |i| vec![i as i32], &mut children);
+            }
+            (
+                Record::PrimitiveArray(old_type, old_vals),
+                Record::PrimitiveArray(new_type, new_vals),
+            ) if old_type == new_type => {
+                for i in 0..old_vals.len().max(new_vals.len()) {
+                    let mut path = path.clone();
+                    path.push(Segment::Index(vec![i as i32]));
+                    let (old, new) = (old_vals.get(i), new_vals.get(i));
+                    children.push(match (old, new) {
+                        (Some(old), Some(new)) => Item::Primitives(path, old, new),
+                        _ => Item::Change(Change {
+                            path,
+                            old: old.map(describe_primitive),
+                            new: new.map(describe_primitive),
+                        }),
+                    });
+                }
+            }
+            (Record::String(old_val), Record::String(new_val)) => {
+                if old_val != new_val {
+                    self.changed(path, (format!("{:?}", old_val), format!("{:?}", new_val)));
+                }
+            }
+            (Record::BinaryLibrary(old_name), Record::BinaryLibrary(new_name)) => {
+                if old_name != new_name {
+                    let change = (
+                        describe_record(self.old, old_id),
+                        describe_record(self.new, new_id),
+                    );
+                    self.changed(path, change);
+                }
+            }
+            _ => {
+                let change = (
+                    describe_record(self.old, old_id),
+                    describe_record(self.new, new_id),
+                );
+                self.changed(path, change);
+            }
+        }
+        self.stack.extend(children.into_iter().rev());
+    }
+
+    fn elements(
+        &self,
+        path: &Path,
+        old_vals: &'a [Member],
+        new_vals: &'a [Member],
+        coords: impl Fn(usize) -> Vec<i32>,
+        children: &mut Vec<Item<'a>>,
+    ) {
+        for i in 0..old_vals.len().max(new_vals.len()) {
+            let mut path = path.clone();
+            path.push(Segment::Index(coords(i)));
+            children.push(match (old_vals.get(i), new_vals.get(i)) {
+                (Some(old), Some(new)) => Item::Members(path, old, new),
+                (Some(old), None) => self.removed(path, old),
+                (None, Some(new)) => self.added(path, new),
+                (None, None) => unreachable!(),
+            });
+        }
+    }
+
+    fn list_items(
+        &self,
+        path: &Path,
+        old: ListView<'a>,
+        new: ListView<'a>,
+        children: &mut Vec<Item<'a>>,
+    ) {
+        for i in 0..old.len().max(new.len()) {
+            let mut path = path.clone();
+            path.push(Segment::Index(vec![i as i32]));
+            children.push(match (old.get(i), new.get(i)) {
+                (Some(Value::Member(old)), Some(Value::Member(new))) => {
+                    Item::Members(path, old, new)
+                }
+                (Some(Value::Primitive(old)), Some(Value::Primitive(new))) => {
+                    Item::Primitives(path, old, new)
+                }
+                (old, new) => Item::Change(Change {
+                    path,
+                    old: old.map(|item| describe_item(self.old, item)),
+                    new: new.map(|item| describe_item(self.new, item)),
+                }),
+            });
+        }
+    }
+
+    fn added(&self, path: Path, member: &Member) -> Item<'a> {
+        Item::Change(Change {
+            path,
+            old: None,
+            new: Some(describe(self.new, member)),
+        })
+    }
+
+    fn removed(&self, path: Path, member: &Member) -> Item<'a> {
+        Item::Change(Change {
+            path,
+            old: Some(describe(self.old, member)),
+            new: None,
+        })
+    }
+
+    /// Whether the classes have the same name and come from libraries with the same name
+    fn same_class_type(&self, old: &Class, new: &Class) -> bool {
+        let (old_type, new_type) = (self.old.class_type(old), self.new.class_type(new));
+        let library = |rec: &DeserializedRecord, class_type: &ClassType| {
+            if class_type.system_class {
+                return None;
+            }
+            match rec.records.get(&class_type.library_id) {
+                Some(Record::BinaryLibrary(name)) => Some(name.clone()),
+                _ => Some(String::new()),
+            }
+        };
+        old_type.name == new_type.name && library(self.old, old_type) == library(self.new, new_type)
+    }
+}
+
+fn is_vector(array: &BinaryArray) -> bool {
+    array.rank() == 1 && array.lower_bounds.iter().all(|&bound| bound == 0)
+}
+
+/// Compare floats by their bits, so that NaNs are equal to themselves and `-0.0` isn't `0.0`.
+fn same_primitive(old: &Primitive, new: &Primitive) -> bool {
+    match (old, new) {
+        (Primitive::Double(old), Primitive::Double(new)) => old.to_bits() == new.to_bits(),
+        (Primitive::Single(old), Primitive::Single(new)) => old.to_bits() == new.to_bits(),
+        _ => old == new,
+    }
+}
+
+/// Include the types if they differ, since e.g. an `Int32` 1 and an `Int64` 1 look the same
+fn describe_primitives(old: &Primitive, new: &Primitive) -> (String, String) {
+    if old.primitive_type() == new.primitive_type() {
+        (describe_primitive(old), describe_primitive(new))
+    } else {
+        (
+            format!("{:?} {}", old.primitive_type(), describe_primitive(old)),
+            format!("{:?} {}", new.primitive_type(), describe_primitive(new)),
+        )
+    }
+}
+
+fn describe_primitive(val: &Primitive) -> String {
+    match val {
+        Primitive::String(s) => format!("{:?}", s),
+        Primitive::Char(c) => format!("{:?}", c),
+        val => val.to_string(),
+    }
+}
+
+/// An item of a list, which is either a member or a primitive.
+fn describe_item(rec: &DeserializedRecord, item: Value) -> String {
+    match item {
+        Value::Member(member) => describe(rec, member),
+        Value::Primitive(val) => describe_primitive(val),
+        Value::Record(_) => unreachable!("List items are members or primitives"),
+    }
+}
+
+fn describe(rec: &DeserializedRecord, member: &Member) -> String {
+    match member {
+        Member::Primitive(val) => describe_primitive(val),
+        Member::Reference(id) => describe_record(rec, *id),
+        Member::Null | Member::NullMultiple(_) => "null".into(),
+    }
+}
+
+fn describe_record(rec: &DeserializedRecord, id: i32) -> String {
+    match rec.records.get(&id) {
+        Some(Record::String(s)) => format!("{:?}", s),
+        Some(Record::Class(class)) => format!("<{}>", rec.class_type(class).name),
+        Some(Record::BinaryLibrary(name)) => format!("<library {}>", name),
+        Some(Record::ObjectArray(vals)) | Some(Record::StringArray(vals)) => {
+            format!("<array of {}>", vals.len())
+        }
+        Some(Record::BinaryArray(array)) => format!("<array of {}>", array.values.len()),
+        Some(Record::PrimitiveArray(typ, vals)) => format!("<{:?} array of {}>", typ, vals.len()),
+        None => format!("<missing record {}>", id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{int, int_array, int_list, save};
+
+    #[test]
+    fn ignores_ids_but_not_values() {
+        let old = save(1);
+        let mut new = save(11);
+        assert!(old.structurally_eq(&new));
+        assert!(old.diff(&new).is_empty());
+
+        // The same values, but no longer shared
+        new.records.insert(15, Record::String("name".into()));
+        if let Some(Record::Class(class)) = new.records.get_mut(&12) {
+            class.members[4] = Member::Reference(15);
+        }
+        assert!(!old.structurally_eq(&new));
+        assert!(old.diff(&new).is_empty());
+
+        if let Some(Record::Class(class)) = new.records.get_mut(&12) {
+            class.members[3] = Member::Primitive(Primitive::Double(-0.5));
+            class.members[4] = Member::Null;
+        }
+        if let Some(Record::PrimitiveArray(_, vals)) = new.records.get_mut(&14) {
+            vals[0] = Primitive::Int32(2);
+            vals.push(Primitive::Int32(3));
+        }
+        new.class_types[0].member_names[0] = "label".into();
+
+        let changes: Vec<String> = old.diff(&new).iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            changes,
+            [
+                "name: removed \"name\"",
+                "values[0]: 1 -> 2",
+                "values[1]: added 3",
+                "ratio: 0.5 -> -0.5",
+                "title: \"name\" -> null",
+                "label: added \"name\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn lists_up_to_size() {
+        let old = int_list();
+        let mut new = int_list();
+        new.records.insert(2, int_array(&[10, 0, 0, 0]));
+        assert!(old.diff(&new).is_empty());
+
+        new.list_mut(1).unwrap().push(int(11)).unwrap();
+        let changes: Vec<String> = old.diff(&new).iter().map(|c| c.to_string()).collect();
+        assert_eq!(changes, ["[1]: added 11", "_version: 7 -> 8"]);
+    }
+}
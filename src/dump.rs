@@ -0,0 +1,219 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+
+use crate::records::*;
+
+/// Print the object graph as an indented tree, starting from the root record.
+///
+/// Records that are referenced from more than one place are labelled with their id
+/// and printed only the first time; later occurrences and cycles are printed as
+/// back-references (`-> #id`). Nesting deeper than `max_depth` is elided with `...`.
+pub fn dump<W: Write>(
+    rec: &DeserializedRecord,
+    out: &mut W,
+    max_depth: Option<usize>,
+) -> io::Result<()> {
+    let mut dumper = Dumper {
+        rec,
+        out,
+        max_depth,
+        ref_counts: ref_counts(rec),
+        shown: HashSet::new(),
+        stack: Vec::new(),
+        todo: Vec::new(),
+    };
+    dumper.record(rec.root_id, 0)?;
+
+    // Walked with an explicit stack instead of recursion, since reference chains in a
+    // save can be longer than the call stack allows
+    while let Some(step) = dumper.todo.pop() {
+        match step {
+            Step::Member(name, member, depth) => {
+                dumper.line(depth)?;
+                write!(dumper.out, "{}: ", name)?;
+                dumper.member(member, depth)?;
+            }
+            Step::Leave => {
+                dumper.stack.pop();
+            }
+        }
+    }
+    writeln!(dumper.out)
+}
+
+enum Step<'a> {
+    /// Write a member on a new line
+    Member(String, &'a Member, usize),
+    /// All children of the innermost record on `stack` were written
+    Leave,
+}
+
+struct Dumper<'a, W> {
+    rec: &'a DeserializedRecord,
+    out: &'a mut W,
+    max_depth: Option<usize>,
+    ref_counts: HashMap<i32, usize>,
+    shown: HashSet<i32>,
+    /// The records whose children are being written, to detect cycles
+    stack: Vec<i32>,
+    todo: Vec<Step<'a>>,
+}
+
+impl<'a, W: Write> Dumper<'a, W> {
+    /// Write a record, starting on the current line. Its children are queued in `todo`.
+    fn record(&mut self, id: i32, depth: usize) -> io::Result<()> {
+        let record = match self.rec.records.get(&id) {
+            Some(record) => record,
+            None => return write!(self.out, "<missing record #{}>", id),
+        };
+        if let Record::String(s) = record {
+            return write!(self.out, "{:?}", s);
+        }
+
+        if self.stack.contains(&id) {
+            return write!(self.out, "-> #{} (cycle)", id);
+        }
+        if self.shown.contains(&id) {
+            return write!(self.out, "-> #{}", id);
+        }
+        if self.ref_counts.get(&id).copied().unwrap_or(0) > 1 {
+            write!(self.out, "#{} ", id)?;
+        }
+
+        let children: Vec<(String, &'a Member)> = match record {
+            Record::Class(class) => {
+                let class_type = self.rec.class_type(class);
+                write!(self.out, "{}", class_type.name)?;
+                class_type
+                    .member_names
+                    .iter()
+                    .cloned()
+                    .zip(&class.members)
+                    .collect()
+            }
+            Record::ObjectArray(vals) | Record::StringArray(vals) => {
+                write!(self.out, "[{}]", vals.len())?;
+                vals.iter()
+                    .enumerate()
+                    .map(|(i, val)| (format!("[{}]", i), val))
+                    .collect()
+            }
+            Record::BinaryArray(array) => {
+                let lengths: Vec<String> = array.lengths.iter().map(|l| l.to_string()).collect();
+                write!(self.out, "[{}]", lengths.join(", "))?;
+                array
+                    .values
+                    .iter()
+                    .enumerate()
+                    .map(|(i, val)| (format!("{:?}", array_coords(array, i)), val))
+                    .collect()
+            }
+            Record::PrimitiveArray(typ, vals) => {
+                write!(self.out, "{:?}[{}]", typ, vals.len())?;
+                if self.elide(depth)? {
+                    return Ok(());
+                }
+                self.shown.insert(id);
+                for (i, val) in vals.iter().enumerate() {
+                    self.line(depth + 1)?;
+                    write!(self.out, "[{}]: {}", i, val)?;
+                }
+                return Ok(());
+            }
+            Record::BinaryLibrary(name) => return write!(self.out, "<library {}>", name),
+            Record::String(_) => unreachable!(),
+        };
+
+        if children.is_empty() || self.elide(depth)? {
+            return Ok(());
+        }
+        self.shown.insert(id);
+        self.stack.push(id);
+        self.todo.push(Step::Leave);
+        for (name, member) in children.into_iter().rev() {
+            self.todo.push(Step::Member(name, member, depth + 1));
+        }
+        Ok(())
+    }
+
+    fn member(&mut self, member: &Member, depth: usize) -> io::Result<()> {
+        match member {
+            Member::Primitive(Primitive::String(s)) => write!(self.out, "{:?}", s),
+            Member::Primitive(Primitive::Char(c)) => write!(self.out, "{:?}", c),
+            Member::Primitive(val) => write!(self.out, "{}", val),
+            Member::Reference(id) => self.record(*id, depth),
+            Member::Null => write!(self.out, "null"),
+            Member::NullMultiple(count) => write!(self.out, "null x{}", count),
+        }
+    }
+
+    /// Write ` ...` and return true if the children at `depth + 1` are too deep to print.
+    fn elide(&mut self, depth: usize) -> io::Result<bool> {
+        if matches!(self.max_depth, Some(max) if depth >= max) {
+            write!(self.out, " ...")?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn line(&mut self, depth: usize) -> io::Result<()> {
+        writeln!(self.out)?;
+        write!(self.out, "{:width$}", "", width = depth * 2)
+    }
+}
+
+/// How often each record is referenced, counting the root once.
+pub(crate) fn ref_counts(rec: &DeserializedRecord) -> HashMap<i32, usize> {
+    let mut counts = HashMap::new();
+    counts.insert(rec.root_id, 1);
+    for record in rec.records.values() {
+        let members: &[Member] = match record {
+            Record::Class(class) => &class.members,
+            Record::ObjectArray(vals) | Record::StringArray(vals) => vals,
+            Record::BinaryArray(array) => &array.values,
+            _ => continue,
+        };
+        for member in members {
+            if let Member::Reference(id) = member {
+                *counts.entry(*id).or_insert(0) += 1;
+            }
+        }
+    }
+    counts
+}
+
+/// The coordinates of the `index`-th value of a (row-major) binary array.
+pub(crate) fn array_coords(array: &BinaryArray, mut index: usize) -> Vec<i32> {
+    let mut coords = vec![0; array.lengths.len()];
+    for dim in (0..coords.len()).rev() {
+        let len = array.lengths[dim].max(1) as usize;
+        coords[dim] = (index % len) as i32 + array.lower_bounds.get(dim).copied().unwrap_or(0);
+        index /= len;
+    }
+    coords
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::document;
+
+    #[test]
+    fn long_reference_chain() {
+        // Object arrays that each hold the next one, with a cycle back to the first
+        let count = 3_000;
+        let records = (1..=count)
+            .map(|id| {
+                let next = if id == count { 1 } else { id + 1 };
+                (id, Record::ObjectArray(vec![Member::Reference(next)]))
+            })
+            .collect();
+        let rec = document(1, records, Vec::new());
+
+        let mut out = Vec::new();
+        dump(&rec, &mut out, None).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), count as usize + 1);
+        assert!(out.ends_with("[0]: -> #1 (cycle)\n"));
+    }
+}
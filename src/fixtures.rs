@@ -0,0 +1,316 @@
+//! Streams and documents shared by the unit tests.
+
+use std::collections::HashMap;
+
+use crate::records::*;
+
+/// A stream with `records` between a header with root 1 and the message end.
+pub fn stream(records: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![
+        0, 1, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 1, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    bytes.extend(records);
+    bytes.push(11);
+    bytes
+}
+
+pub fn push_string(bytes: &mut Vec<u8>, s: &str) {
+    bytes.push(s.len() as u8);
+    bytes.extend(s.as_bytes());
+}
+
+pub fn push_i32(bytes: &mut Vec<u8>, i: i32) {
+    bytes.extend(&i.to_le_bytes());
+}
+
+/// A stream laid out the way .NET would write it, with inlined records, forward
+/// references, reused class metadata, a library inside a member and null runs.
+pub fn sample_stream() -> Vec<u8> {
+    let mut b = Vec::new();
+    b.push(12);
+    push_i32(&mut b, 2);
+    push_string(&mut b, "Assembly");
+
+    // Save { name: String, items: List, other: Object, count: Int32 }
+    b.push(5);
+    push_i32(&mut b, 1);
+    push_string(&mut b, "Save");
+    push_i32(&mut b, 4);
+    for name in &["name", "items", "other", "count"] {
+        push_string(&mut b, name);
+    }
+    b.extend(&[1, 3, 2, 0]);
+    push_string(&mut b, "List");
+    b.push(8);
+    push_i32(&mut b, 2);
+
+    b.push(6);
+    push_i32(&mut b, 3);
+    push_string(&mut b, "save");
+
+    // List { _items: Object[], _size: Int32, _version: Int32 }
+    b.push(4);
+    push_i32(&mut b, 4);
+    push_string(&mut b, "List");
+    push_i32(&mut b, 3);
+    for name in &["_items", "_size", "_version"] {
+        push_string(&mut b, name);
+    }
+    b.extend(&[5, 0, 0, 8, 8]);
+    b.push(9);
+    push_i32(&mut b, 5);
+    push_i32(&mut b, 2);
+    push_i32(&mut b, 3);
+
+    b.push(12);
+    push_i32(&mut b, 6);
+    push_string(&mut b, "OtherAssembly");
+    b.push(5);
+    push_i32(&mut b, 7);
+    push_string(&mut b, "Other");
+    push_i32(&mut b, 1);
+    push_string(&mut b, "value");
+    b.extend(&[0, 8]);
+    push_i32(&mut b, 6);
+    push_i32(&mut b, 7);
+
+    push_i32(&mut b, 42);
+
+    b.push(16);
+    push_i32(&mut b, 5);
+    push_i32(&mut b, 5);
+    b.push(1);
+    push_i32(&mut b, 8);
+    push_i32(&mut b, 7);
+    push_i32(&mut b, 8);
+    b.extend(&[10, 13, 2, 9]);
+    push_i32(&mut b, 9);
+
+    b.push(1);
+    push_i32(&mut b, 9);
+    push_i32(&mut b, 4);
+    b.push(9);
+    push_i32(&mut b, 10);
+    push_i32(&mut b, 0);
+    push_i32(&mut b, 0);
+
+    b.push(7);
+    push_i32(&mut b, 10);
+    b.push(0);
+    push_i32(&mut b, 1);
+    push_i32(&mut b, 300);
+    b.push(2);
+    b.push(14);
+    push_i32(&mut b, 300);
+
+    stream(&b)
+}
+
+/// A stream with a single `ArraySinglePrimitive` of chars as its root.
+pub fn char_array(encoded: &[u8], count: u8) -> Vec<u8> {
+    let mut records = vec![15, 1, 0, 0, 0, count, 0, 0, 0, 3];
+    records.extend(encoded);
+    stream(&records)
+}
+
+pub fn int(val: i32) -> Member {
+    Member::Primitive(Primitive::Int32(val))
+}
+
+pub fn int_array(vals: &[i32]) -> Record {
+    let vals = vals.iter().map(|&v| Primitive::Int32(v)).collect();
+    Record::PrimitiveArray(PrimitiveType::Int32, vals)
+}
+
+pub fn class(class_type_id: usize, members: Vec<Member>) -> Record {
+    Record::Class(Class {
+        class_type_id,
+        members,
+    })
+}
+
+/// A system class, which doesn't need a library.
+pub fn class_type(name: &str, members: &[(&str, MemberType)]) -> ClassType {
+    ClassType {
+        name: name.into(),
+        library_id: 0,
+        system_class: true,
+        has_member_types: true,
+        member_names: members.iter().map(|(n, _)| n.to_string()).collect(),
+        member_types: members.iter().map(|(_, t)| t.clone()).collect(),
+    }
+}
+
+/// A class from the library with the given id.
+pub fn library_class_type(
+    name: &str,
+    library_id: i32,
+    members: &[(&str, MemberType)],
+) -> ClassType {
+    ClassType {
+        library_id,
+        system_class: false,
+        ..class_type(name, members)
+    }
+}
+
+/// A document without layout, as if it was built from scratch.
+pub fn document(
+    root_id: i32,
+    records: Vec<(i32, Record)>,
+    class_types: Vec<ClassType>,
+) -> DeserializedRecord {
+    DeserializedRecord {
+        root_id,
+        header_id: -1,
+        records: records.into_iter().collect::<HashMap<_, _>>(),
+        class_types,
+        layout: Layout::default(),
+        spans: None,
+    }
+}
+
+/// A `Save { name, values, count, ratio, title }` whose `name` and `title` are the same
+/// string record. The record ids start at `first_id`, with the root at `first_id + 1`.
+pub fn save(first_id: i32) -> DeserializedRecord {
+    let id = |offset: i32| first_id + offset;
+    document(
+        id(1),
+        vec![
+            (id(0), Record::BinaryLibrary("Assembly".into())),
+            (
+                id(1),
+                class(
+                    0,
+                    vec![
+                        Member::Reference(id(2)),
+                        Member::Reference(id(3)),
+                        int(5),
+                        Member::Primitive(Primitive::Double(0.5)),
+                        Member::Reference(id(2)),
+                    ],
+                ),
+            ),
+            (id(2), Record::String("name".into())),
+            (id(3), int_array(&[1])),
+        ],
+        vec![library_class_type(
+            "Save",
+            id(0),
+            &[
+                ("name", MemberType::String),
+                ("values", MemberType::PrimitiveArray(PrimitiveType::Int32)),
+                ("count", MemberType::Primitive(PrimitiveType::Int32)),
+                ("ratio", MemberType::Primitive(PrimitiveType::Double)),
+                ("title", MemberType::String),
+            ],
+        )],
+    )
+}
+
+/// A `List<int>` with the element 10 and a capacity of 2 as record 1, its `_items` as 2.
+pub fn int_list() -> DeserializedRecord {
+    let int32 = || MemberType::Primitive(PrimitiveType::Int32);
+    document(
+        1,
+        vec![
+            (1, class(0, vec![Member::Reference(2), int(1), int(7)])),
+            (2, int_array(&[10, 0])),
+        ],
+        vec![class_type(
+            "System.Collections.Generic.List`1[[System.Int32]]",
+            &[
+                ("_items", MemberType::PrimitiveArray(PrimitiveType::Int32)),
+                ("_size", int32()),
+                ("_version", int32()),
+            ],
+        )],
+    )
+}
+
+pub const DICTIONARY: &str =
+    "System.Collections.Generic.Dictionary`2[[System.String],[System.Int32]]";
+pub const PAIR: &str = "System.Collections.Generic.KeyValuePair`2[[System.String],[System.Int32]]";
+const HASH_SET: &str = "System.Collections.Generic.HashSet`1[[System.String]]";
+
+/// A save with a dictionary with one entry (2), a dictionary that never had entries (5)
+/// and an empty hash set (6).
+pub fn collections() -> DeserializedRecord {
+    let int32 = || MemberType::Primitive(PrimitiveType::Int32);
+    let comparer = || MemberType::SystemClass("Comparer".into());
+    document(
+        1,
+        vec![
+            (
+                1,
+                class(
+                    0,
+                    vec![
+                        Member::Reference(2),
+                        Member::Reference(5),
+                        Member::Reference(6),
+                    ],
+                ),
+            ),
+            (
+                2,
+                class(1, vec![int(3), Member::Null, int(3), Member::Reference(3)]),
+            ),
+            (
+                3,
+                Record::BinaryArray(BinaryArray {
+                    array_type: BinaryArrayType::Single,
+                    lengths: vec![1],
+                    lower_bounds: Vec::new(),
+                    member_type: MemberType::SystemClass(PAIR.into()),
+                    values: vec![Member::Reference(4)],
+                }),
+            ),
+            (4, class(2, vec![Member::Reference(7), int(5)])),
+            (7, Record::String("gold".into())),
+            (5, class(3, vec![int(0), Member::Null, int(0)])),
+            (6, class(4, vec![int(1), Member::Null, int(0)])),
+            (10, Record::BinaryLibrary("Assembly".into())),
+        ],
+        vec![
+            library_class_type(
+                "Save",
+                10,
+                &[
+                    ("dict", MemberType::SystemClass(DICTIONARY.into())),
+                    ("empty", MemberType::SystemClass(DICTIONARY.into())),
+                    ("set", MemberType::SystemClass(HASH_SET.into())),
+                ],
+            ),
+            class_type(
+                DICTIONARY,
+                &[
+                    ("Version", int32()),
+                    ("Comparer", comparer()),
+                    ("HashSize", int32()),
+                    (
+                        "KeyValuePairs",
+                        MemberType::SystemClass(format!("{}[]", PAIR)),
+                    ),
+                ],
+            ),
+            class_type(PAIR, &[("key", MemberType::String), ("value", int32())]),
+            class_type(
+                DICTIONARY,
+                &[
+                    ("Version", int32()),
+                    ("Comparer", comparer()),
+                    ("HashSize", int32()),
+                ],
+            ),
+            class_type(
+                HASH_SET,
+                &[
+                    ("Version", int32()),
+                    ("Comparer", comparer()),
+                    ("Capacity", int32()),
+                ],
+            ),
+        ],
+    )
+}
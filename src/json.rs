@@ -0,0 +1,761 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+
+use serde_json::{json, Map, Value};
+
+use crate::dump::ref_counts;
+use crate::path;
+use crate::records::*;
+
+/// A JSON document that doesn't describe a valid `DeserializedRecord`.
+#[derive(Debug)]
+pub struct JsonError {
+    /// Where in the document the problem is, e.g. `records.5.members[2]`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+/// Export a whole stream as JSON, including its layout.
+///
+/// Nothing is lost: `from_json(&to_json(x))` serializes to the same bytes as `x`.
+pub fn to_json(rec: &DeserializedRecord) -> Value {
+    let class_types: Vec<Value> = rec
+        .class_types
+        .iter()
+        .map(|class_type| {
+            let members: Vec<Value> = class_type
+                .member_names
+                .iter()
+                .zip(&class_type.member_types)
+                .map(|(name, typ)| json!({ "name": name, "type": member_type_to_json(typ) }))
+                .collect();
+            json!({
+                "name": class_type.name,
+                "library_id": class_type.library_id,
+                "system_class": class_type.system_class,
+                "has_member_types": class_type.has_member_types,
+                "members": members,
+            })
+        })
+        .collect();
+
+    let records: Map<String, Value> = rec
+        .records
+        .iter()
+        .map(|(id, record)| (id.to_string(), record_to_json(record)))
+        .collect();
+
+    json!({
+        "header_id": rec.header_id,
+        "root_id": rec.root_id,
+        "class_types": class_types,
+        "records": records,
+        "layout": layout_to_json(&rec.layout),
+    })
+}
+
+/// Rebuild a stream from the output of `to_json`.
+pub fn from_json(value: &Value) -> Result<DeserializedRecord, JsonError> {
+    let class_types = array(field(value, "class_types", "")?, "class_types")?
+        .iter()
+        .enumerate()
+        .map(|(i, class_type)| class_type_from_json(class_type, &format!("class_types[{}]", i)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut records = HashMap::new();
+    let records_json = field(value, "records", "")?;
+    let records_json = records_json
+        .as_object()
+        .ok_or_else(|| invalid("records", "expected an object"))?;
+    for (id, record) in records_json {
+        let path = format!("records.{}", id);
+        let id = id
+            .parse()
+            .map_err(|_| invalid(&path, "record ids must be integers"))?;
+        let record = record_from_json(record, &path)?;
+        if let Record::Class(class) = &record {
+            if class.class_type_id >= class_types.len() {
+                return Err(invalid(&path, "unknown class type"));
+            }
+        }
+        records.insert(id, record);
+    }
+
+    Ok(DeserializedRecord {
+        root_id: int(field(value, "root_id", "")?, "root_id")?,
+        header_id: int(field(value, "header_id", "")?, "header_id")?,
+        records,
+        class_types,
+        layout: layout_from_json(field(value, "layout", "")?, "layout")?,
+        spans: None,
+    })
+}
+
+/// Export a read-friendly projection of the object graph, starting at the root record.
+///
+/// Classes become objects keyed by member name, arrays become arrays and references are
+/// inlined. A `List<T>` is shown as an array of its first `_size` items. Records referenced
+/// from more than one place get an `"$id"` the first time and are shown as `{"$ref": id}`
+/// afterwards, as are cycles. Shared arrays and lists are wrapped into
+/// `{"$id": id, "$values": [...]}` for that. Records nested deeper than `MAX_OBJECT_DEPTH`
+/// are shown as `{"$elided": id}`. This can't be imported again.
+pub fn to_object_json(rec: &DeserializedRecord) -> Value {
+    let mut view = ObjectView {
+        rec,
+        ref_counts: ref_counts(rec),
+        shown: HashSet::new(),
+    };
+    view.record(rec.root_id, 0)
+}
+
+/// How deeply `to_object_json` nests records. Like serde_json's own recursion limit, this
+/// keeps long reference chains from overflowing the stack, both here and when the result
+/// is serialized.
+pub const MAX_OBJECT_DEPTH: usize = 128;
+
+struct ObjectView<'a> {
+    rec: &'a DeserializedRecord,
+    ref_counts: HashMap<i32, usize>,
+    shown: HashSet<i32>,
+}
+
+impl ObjectView<'_> {
+    fn record(&mut self, id: i32, depth: usize) -> Value {
+        let record = match self.rec.records.get(&id) {
+            Some(record) => record,
+            None => return json!({ "$missing": id }),
+        };
+        if let Record::String(s) = record {
+            return json!(s);
+        }
+        if depth >= MAX_OBJECT_DEPTH && !self.shown.contains(&id) {
+            return json!({ "$elided": id });
+        }
+        if !self.shown.insert(id) {
+            return json!({ "$ref": id });
+        }
+
+        match record {
+            Record::Class(class) => {
+                if let Some(items) = self.list_items(id, depth) {
+                    return self.array(id, Value::Array(items));
+                }
+                let class_type = self.rec.class_type(class);
+                let mut obj = Map::new();
+                if self.is_shared(id) {
+                    obj.insert("$id".into(), json!(id));
+                }
+                for (name, member) in class_type.member_names.iter().zip(&class.members) {
+                    obj.insert(name.clone(), self.member(member, depth));
+                }
+                Value::Object(obj)
+            }
+            Record::ObjectArray(vals) | Record::StringArray(vals) => {
+                let vals = vals.iter().map(|val| self.member(val, depth)).collect();
+                self.array(id, Value::Array(vals))
+            }
+            Record::BinaryArray(array) => {
+                let vals: Vec<Value> = array
+                    .values
+                    .iter()
+                    .map(|val| self.member(val, depth))
+                    .collect();
+                self.array(id, nest(vals, &array.lengths))
+            }
+            Record::PrimitiveArray(_, vals) => self.array(
+                id,
+                Value::Array(vals.iter().map(primitive_to_json).collect()),
+            ),
+            Record::BinaryLibrary(name) => json!(name),
+            Record::String(_) => unreachable!(),
+        }
+    }
+
+    /// A member of a record at `depth`.
+    fn member(&mut self, member: &Member, depth: usize) -> Value {
+        match member {
+            Member::Primitive(val) => primitive_to_json(val),
+            Member::Reference(id) => self.record(*id, depth + 1),
+            Member::Null | Member::NullMultiple(_) => Value::Null,
+        }
+    }
+
+    fn is_shared(&self, id: i32) -> bool {
+        self.ref_counts.get(&id).copied().unwrap_or(0) > 1
+    }
+
+    /// The values of an array or list, wrapped with its id if a `$ref` can point to it.
+    fn array(&self, id: i32, values: Value) -> Value {
+        if self.is_shared(id) {
+            json!({ "$id": id, "$values": values })
+        } else {
+            values
+        }
+    }
+
+    /// The items of a `List<T>`, or `None` if the record isn't a list.
+    fn list_items(&mut self, id: i32, depth: usize) -> Option<Vec<Value>> {
+        let rec = self.rec;
+        let list = rec.list(id)?;
+        let items = list
+            .iter()
+            .map(|item| match item {
+                path::Value::Member(member) => self.member(member, depth),
+                path::Value::Primitive(val) => primitive_to_json(val),
+                path::Value::Record(_) => unreachable!(),
+            })
+            .collect();
+        Some(items)
+    }
+}
+
+/// Split the row-major values of a multi-dimensional array into nested arrays.
+fn nest(vals: Vec<Value>, lengths: &[i32]) -> Value {
+    if lengths.len() <= 1 {
+        return Value::Array(vals);
+    }
+    let chunk = vals.len() / (lengths[0].max(1) as usize);
+    if chunk == 0 {
+        return Value::Array(Vec::new());
+    }
+    let mut vals = vals.into_iter();
+    let mut nested = Vec::new();
+    loop {
+        let part: Vec<Value> = vals.by_ref().take(chunk).collect();
+        if part.is_empty() {
+            break;
+        }
+        nested.push(nest(part, &lengths[1..]));
+    }
+    Value::Array(nested)
+}
+
+fn record_to_json(record: &Record) -> Value {
+    match record {
+        Record::BinaryLibrary(name) => json!({ "type": "library", "name": name }),
+        Record::Class(class) => json!({
+            "type": "class",
+            "class_type": class.class_type_id,
+            "members": members_to_json(&class.members),
+        }),
+        Record::ObjectArray(vals) => json!({
+            "type": "object_array",
+            "values": members_to_json(vals),
+        }),
+        Record::BinaryArray(array) => json!({
+            "type": "binary_array",
+            "array_type": format!("{:?}", array.array_type),
+            "lengths": array.lengths,
+            "lower_bounds": array.lower_bounds,
+            "member_type": member_type_to_json(&array.member_type),
+            "values": members_to_json(&array.values),
+        }),
+        Record::PrimitiveArray(typ, vals) => json!({
+            "type": "primitive_array",
+            "primitive_type": format!("{:?}", typ),
+            "values": vals.iter().map(primitive_to_json).collect::<Vec<_>>(),
+        }),
+        Record::StringArray(vals) => json!({
+            "type": "string_array",
+            "values": members_to_json(vals),
+        }),
+        Record::String(s) => json!({ "type": "string", "value": s }),
+    }
+}
+
+fn record_from_json(value: &Value, path: &str) -> Result<Record, JsonError> {
+    let values = |name| -> Result<Vec<Member>, JsonError> {
+        members_from_json(field(value, name, path)?, &format!("{}.{}", path, name))
+    };
+    Ok(match string(field(value, "type", path)?, path)? {
+        "library" => Record::BinaryLibrary(string(field(value, "name", path)?, path)?.into()),
+        "class" => Record::Class(Class {
+            class_type_id: usize(field(value, "class_type", path)?, path)?,
+            members: values("members")?,
+        }),
+        "object_array" => Record::ObjectArray(values("values")?),
+        "binary_array" => {
+            let array_type = match string(field(value, "array_type", path)?, path)? {
+                "Single" => BinaryArrayType::Single,
+                "Jagged" => BinaryArrayType::Jagged,
+                "Rectangular" => BinaryArrayType::Rectangular,
+                "SingleOffset" => BinaryArrayType::SingleOffset,
+                "JaggedOffset" => BinaryArrayType::JaggedOffset,
+                "RectangularOffset" => BinaryArrayType::RectangularOffset,
+                other => return Err(invalid(path, format!("unknown array type '{}'", other))),
+            };
+            let ints = |name| -> Result<Vec<i32>, JsonError> {
+                let field = field(value, name, path)?;
+                let path = format!("{}.{}", path, name);
+                array(field, &path)?.iter().map(|v| int(v, &path)).collect()
+            };
+            Record::BinaryArray(BinaryArray {
+                array_type,
+                lengths: ints("lengths")?,
+                lower_bounds: ints("lower_bounds")?,
+                member_type: member_type_from_json(field(value, "member_type", path)?, path)?,
+                values: values("values")?,
+            })
+        }
+        "primitive_array" => {
+            let typ =
+                primitive_type_from_name(string(field(value, "primitive_type", path)?, path)?)
+                    .ok_or_else(|| invalid(path, "unknown primitive type"))?;
+            let vals = field(value, "values", path)?;
+            let path = format!("{}.values", path);
+            let vals = array(vals, &path)?
+                .iter()
+                .enumerate()
+                .map(|(i, v)| primitive_from_json(&typ, v, &format!("{}[{}]", path, i)))
+                .collect::<Result<_, _>>()?;
+            Record::PrimitiveArray(typ, vals)
+        }
+        "string_array" => Record::StringArray(values("values")?),
+        "string" => Record::String(string(field(value, "value", path)?, path)?.into()),
+        other => return Err(invalid(path, format!("unknown record type '{}'", other))),
+    })
+}
+
+/// Members are `null`, `{"ref": id}`, `{"nulls": count}` or a primitive tagged with its
+/// type, e.g. `{"Int32": 5}`.
+fn members_to_json(members: &[Member]) -> Vec<Value> {
+    members
+        .iter()
+        .map(|member| match member {
+            Member::Primitive(val) => {
+                let mut obj = Map::new();
+                obj.insert(
+                    format!("{:?}", val.primitive_type()),
+                    primitive_to_json(val),
+                );
+                Value::Object(obj)
+            }
+            Member::Reference(id) => json!({ "ref": id }),
+            Member::Null => Value::Null,
+            Member::NullMultiple(count) => json!({ "nulls": count }),
+        })
+        .collect()
+}
+
+fn members_from_json(value: &Value, path: &str) -> Result<Vec<Member>, JsonError> {
+    array(value, path)?
+        .iter()
+        .enumerate()
+        .map(|(i, member)| {
+            let path = format!("{}[{}]", path, i);
+            let obj = match member {
+                Value::Null => return Ok(Member::Null),
+                Value::Object(obj) if obj.len() == 1 => obj,
+                _ => {
+                    return Err(invalid(
+                        &path,
+                        "expected null or an object with a single key",
+                    ))
+                }
+            };
+            let (key, val) = obj.iter().next().unwrap();
+            Ok(match key.as_str() {
+                "ref" => Member::Reference(int(val, &path)?),
+                "nulls" => Member::NullMultiple(int(val, &path)?),
+                name => {
+                    let typ = primitive_type_from_name(name).ok_or_else(|| {
+                        invalid(&path, format!("unknown primitive type '{}'", name))
+                    })?;
+                    Member::Primitive(primitive_from_json(&typ, val, &path)?)
+                }
+            })
+        })
+        .collect()
+}
+
+/// Floats that JSON can't represent are written as strings: `inf`, `-inf` and `NaN:<bits>`
+/// since .NET uses a different NaN than Rust.
+fn primitive_to_json(val: &Primitive) -> Value {
+    fn float(val: f64, bits: u64, text: String) -> Value {
+        if val.is_nan() {
+            Value::String(format!("NaN:{:#x}", bits))
+        } else if val.is_infinite() {
+            Value::String(text)
+        } else {
+            // Parse the shortest representation so singles don't pick up f64 noise
+            Value::Number(text.parse().unwrap())
+        }
+    }
+
+    match val {
+        Primitive::Boolean(val) => json!(val),
+        Primitive::Byte(val) => json!(val),
+        Primitive::Char(val) => json!(val),
+        Primitive::Decimal(val) => json!(val),
+        Primitive::Double(val) => float(*val, val.to_bits(), format!("{:?}", val)),
+        Primitive::Int16(val) => json!(val),
+        Primitive::Int32(val) => json!(val),
+        Primitive::Int64(val) => json!(val),
+        Primitive::Int8(val) => json!(val),
+        Primitive::Single(val) => float(*val as f64, val.to_bits() as u64, format!("{:?}", val)),
+        Primitive::TimeSpan(val) => json!(val),
+        Primitive::DateTime(val) => json!(val),
+        Primitive::UInt16(val) => json!(val),
+        Primitive::UInt32(val) => json!(val),
+        Primitive::UInt64(val) => json!(val),
+        Primitive::Null => Value::Null,
+        Primitive::String(val) => json!(val),
+    }
+}
+
+fn primitive_from_json(
+    typ: &PrimitiveType,
+    value: &Value,
+    path: &str,
+) -> Result<Primitive, JsonError> {
+    let text = match value {
+        Value::Bool(val) => val.to_string(),
+        Value::Number(val) => val.to_string(),
+        Value::String(val) => val.clone(),
+        Value::Null => "null".into(),
+        _ => return Err(invalid(path, "expected a primitive value")),
+    };
+    if let Some(bits) = text.strip_prefix("NaN:0x") {
+        let bits = u64::from_str_radix(bits, 16).map_err(|_| invalid(path, "invalid NaN"))?;
+        match typ {
+            PrimitiveType::Double => return Ok(Primitive::Double(f64::from_bits(bits))),
+            PrimitiveType::Single => return Ok(Primitive::Single(f32::from_bits(bits as u32))),
+            _ => (),
+        }
+    }
+    match (typ, value) {
+        (PrimitiveType::Decimal, Value::String(val)) => Ok(Primitive::Decimal(val.clone())),
+        (PrimitiveType::String, Value::String(val)) => Ok(Primitive::String(val.clone())),
+        (PrimitiveType::Decimal, _) | (PrimitiveType::String, _) => {
+            Err(invalid(path, "expected a string"))
+        }
+        _ => Primitive::parse(typ, &text).map_err(|err| invalid(path, err.to_string())),
+    }
+}
+
+/// Member types are written as their name, or as `{"<name>": <argument>}` if they have one.
+fn member_type_to_json(typ: &MemberType) -> Value {
+    match typ {
+        MemberType::Primitive(typ) => json!({ "Primitive": format!("{:?}", typ) }),
+        MemberType::String => json!("String"),
+        MemberType::Object => json!("Object"),
+        MemberType::SystemClass(name) => json!({ "SystemClass": name }),
+        MemberType::Class(name, library_id) => json!({ "Class": [name, library_id] }),
+        MemberType::ObjectArray => json!("ObjectArray"),
+        MemberType::StringArray => json!("StringArray"),
+        MemberType::PrimitiveArray(typ) => json!({ "PrimitiveArray": format!("{:?}", typ) }),
+    }
+}
+
+fn member_type_from_json(value: &Value, path: &str) -> Result<MemberType, JsonError> {
+    let primitive = |v: &Value| {
+        primitive_type_from_name(string(v, path)?)
+            .ok_or_else(|| invalid(path, "unknown primitive type"))
+    };
+    match value {
+        Value::String(name) => match name.as_str() {
+            "String" => Ok(MemberType::String),
+            "Object" => Ok(MemberType::Object),
+            "ObjectArray" => Ok(MemberType::ObjectArray),
+            "StringArray" => Ok(MemberType::StringArray),
+            _ => Err(invalid(path, format!("unknown member type '{}'", name))),
+        },
+        Value::Object(obj) if obj.len() == 1 => {
+            let (name, arg) = obj.iter().next().unwrap();
+            match name.as_str() {
+                "Primitive" => Ok(MemberType::Primitive(primitive(arg)?)),
+                "SystemClass" => Ok(MemberType::SystemClass(string(arg, path)?.into())),
+                "Class" => match array(arg, path)?.as_slice() {
+                    [name, library_id] => Ok(MemberType::Class(
+                        string(name, path)?.into(),
+                        int(library_id, path)?,
+                    )),
+                    _ => Err(invalid(path, "expected [name, library_id]")),
+                },
+                "PrimitiveArray" => Ok(MemberType::PrimitiveArray(primitive(arg)?)),
+                _ => Err(invalid(path, format!("unknown member type '{}'", name))),
+            }
+        }
+        _ => Err(invalid(path, "expected a member type")),
+    }
+}
+
+fn primitive_type_from_name(name: &str) -> Option<PrimitiveType> {
+    Some(match name {
+        "Boolean" => PrimitiveType::Boolean,
+        "Byte" => PrimitiveType::Byte,
+        "Char" => PrimitiveType::Char,
+        "Decimal" => PrimitiveType::Decimal,
+        "Double" => PrimitiveType::Double,
+        "Int16" => PrimitiveType::Int16,
+        "Int32" => PrimitiveType::Int32,
+        "Int64" => PrimitiveType::Int64,
+        "Int8" => PrimitiveType::Int8,
+        "Single" => PrimitiveType::Single,
+        "TimeSpan" => PrimitiveType::TimeSpan,
+        "DateTime" => PrimitiveType::DateTime,
+        "UInt16" => PrimitiveType::UInt16,
+        "UInt32" => PrimitiveType::UInt32,
+        "UInt64" => PrimitiveType::UInt64,
+        "Null" => PrimitiveType::Null,
+        "String" => PrimitiveType::String,
+        _ => return None,
+    })
+}
+
+fn class_type_from_json(value: &Value, path: &str) -> Result<ClassType, JsonError> {
+    let mut member_names = Vec::new();
+    let mut member_types = Vec::new();
+    let members_path = format!("{}.members", path);
+    for (i, member) in array(field(value, "members", path)?, &members_path)?
+        .iter()
+        .enumerate()
+    {
+        let path = format!("{}[{}]", members_path, i);
+        member_names.push(string(field(member, "name", &path)?, &path)?.into());
+        member_types.push(member_type_from_json(field(member, "type", &path)?, &path)?);
+    }
+    Ok(ClassType {
+        name: string(field(value, "name", path)?, path)?.into(),
+        library_id: int(field(value, "library_id", path)?, path)?,
+        system_class: boolean(field(value, "system_class", path)?, path)?,
+        has_member_types: boolean(field(value, "has_member_types", path)?, path)?,
+        member_names,
+        member_types,
+    })
+}
+
+fn layout_to_json(layout: &Layout) -> Value {
+    let mut inlined: Vec<_> = layout.inlined.iter().collect();
+    inlined.sort_by_key(|(key, _)| **key);
+    let inlined: Vec<Value> = inlined
+        .into_iter()
+        .map(|((parent, index), ids)| json!({ "parent": parent, "index": index, "records": ids }))
+        .collect();
+
+    let mut class_with_id: Vec<i32> = layout.class_with_id.iter().copied().collect();
+    class_with_id.sort_unstable();
+
+    let mut null_runs: Vec<_> = layout.null_runs.iter().collect();
+    null_runs.sort_by_key(|(key, _)| **key);
+    let null_runs: Vec<Value> = null_runs
+        .into_iter()
+        .map(|((parent, index), run)| {
+            json!({
+                "parent": parent,
+                "index": index,
+                "count": run.count(),
+                "wide": matches!(run, NullRun::Wide(_)),
+            })
+        })
+        .collect();
+
+    json!({
+        "records": layout.records,
+        "inlined": inlined,
+        "class_with_id": class_with_id,
+        "null_runs": null_runs,
+    })
+}
+
+fn layout_from_json(value: &Value, path: &str) -> Result<Layout, JsonError> {
+    let ints = |v: &Value, path: &str| -> Result<Vec<i32>, JsonError> {
+        array(v, path)?.iter().map(|v| int(v, path)).collect()
+    };
+    let key = |v: &Value, path: &str| -> Result<(i32, usize), JsonError> {
+        Ok((
+            int(field(v, "parent", path)?, path)?,
+            usize(field(v, "index", path)?, path)?,
+        ))
+    };
+
+    let mut inlined = HashMap::new();
+    let inlined_path = format!("{}.inlined", path);
+    for (i, entry) in array(field(value, "inlined", path)?, &inlined_path)?
+        .iter()
+        .enumerate()
+    {
+        let path = format!("{}[{}]", inlined_path, i);
+        inlined.insert(
+            key(entry, &path)?,
+            ints(field(entry, "records", &path)?, &path)?,
+        );
+    }
+
+    let mut null_runs = HashMap::new();
+    let null_runs_path = format!("{}.null_runs", path);
+    for (i, entry) in array(field(value, "null_runs", path)?, &null_runs_path)?
+        .iter()
+        .enumerate()
+    {
+        let path = format!("{}[{}]", null_runs_path, i);
+        let count = int(field(entry, "count", &path)?, &path)?;
+        let run = if boolean(field(entry, "wide", &path)?, &path)? {
+            NullRun::Wide(count)
+        } else {
+            NullRun::Short(u8::try_from(count).map_err(|_| invalid(&path, "count too large"))?)
+        };
+        null_runs.insert(key(entry, &path)?, run);
+    }
+
+    let class_with_id_path = format!("{}.class_with_id", path);
+    let class_with_id: HashSet<i32> =
+        ints(field(value, "class_with_id", path)?, &class_with_id_path)?
+            .into_iter()
+            .collect();
+
+    Ok(Layout {
+        records: ints(field(value, "records", path)?, &format!("{}.records", path))?,
+        inlined,
+        class_with_id,
+        null_runs,
+    })
+}
+
+fn invalid(path: &str, message: impl Into<String>) -> JsonError {
+    JsonError {
+        path: path.into(),
+        message: message.into(),
+    }
+}
+
+fn field<'a>(value: &'a Value, name: &str, path: &str) -> Result<&'a Value, JsonError> {
+    value
+        .get(name)
+        .ok_or_else(|| invalid(path, format!("missing field '{}'", name)))
+}
+
+fn array<'a>(value: &'a Value, path: &str) -> Result<&'a Vec<Value>, JsonError> {
+    value
+        .as_array()
+        .ok_or_else(|| invalid(path, "expected an array"))
+}
+
+fn string<'a>(value: &'a Value, path: &str) -> Result<&'a str, JsonError> {
+    value
+        .as_str()
+        .ok_or_else(|| invalid(path, "expected a string"))
+}
+
+fn boolean(value: &Value, path: &str) -> Result<bool, JsonError> {
+    value
+        .as_bool()
+        .ok_or_else(|| invalid(path, "expected a boolean"))
+}
+
+fn int(value: &Value, path: &str) -> Result<i32, JsonError> {
+    value
+        .as_i64()
+        .and_then(|v| i32::try_from(v).ok())
+        .ok_or_else(|| invalid(path, "expected a 32-bit integer"))
+}
+
+fn usize(value: &Value, path: &str) -> Result<usize, JsonError> {
+    value
+        .as_u64()
+        .and_then(|v| usize::try_from(v).ok())
+        .ok_or_else(|| invalid(path, "expected a non-negative integer"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{class, document, int_array, int_list, library_class_type};
+    use crate::{parse, serialize};
+
+    #[test]
+    fn round_trip_odd_floats() {
+        let doubles = [
+            f64::from_bits(0xfff8_0000_0000_0000),
+            -0.0,
+            f64::INFINITY,
+            0.1,
+        ];
+        let singles = [f32::from_bits(0x7fc0_0001), f32::NEG_INFINITY, 0.1];
+        let mut members: Vec<Member> = doubles
+            .iter()
+            .map(|&v| Member::Primitive(Primitive::Double(v)))
+            .collect();
+        members.extend(
+            singles
+                .iter()
+                .map(|&v| Member::Primitive(Primitive::Single(v))),
+        );
+        let names: Vec<String> = (0..members.len()).map(|i| format!("v{}", i)).collect();
+        let member_types: Vec<(&str, MemberType)> = names
+            .iter()
+            .zip(&members)
+            .map(|(name, m)| match m {
+                Member::Primitive(p) => (name.as_str(), MemberType::Primitive(p.primitive_type())),
+                _ => unreachable!(),
+            })
+            .collect();
+
+        let rec = document(
+            2,
+            vec![
+                (1, Record::BinaryLibrary("Lib".into())),
+                (2, class(0, members)),
+            ],
+            vec![library_class_type("Floats", 1, &member_types)],
+        );
+
+        let bytes = serialize(&rec);
+        let rec = parse(&bytes).unwrap();
+        let text = serde_json::to_string(&to_json(&rec)).unwrap();
+        let imported = from_json(&serde_json::from_str(&text).unwrap()).unwrap();
+        assert_eq!(serialize(&imported), bytes);
+        assert!(text.contains(r#"{"Single":0.1}"#), "{}", text);
+    }
+
+    #[test]
+    fn object_depth_limit() {
+        // Object arrays that each hold the next one
+        let records = (1..=10_000)
+            .map(|id| (id, Record::ObjectArray(vec![Member::Reference(id + 1)])))
+            .collect();
+        let rec = document(1, records, Vec::new());
+
+        let json = to_object_json(&rec);
+        let mut value = &json;
+        for _ in 0..MAX_OBJECT_DEPTH {
+            value = &value[0];
+        }
+        assert_eq!(*value, json!({ "$elided": MAX_OBJECT_DEPTH + 1 }));
+        assert!(serde_json::to_string(&json).is_ok());
+    }
+
+    #[test]
+    fn shared_arrays_and_lists() {
+        let mut rec = int_list();
+        let refs = [1, 1, 3, 3, 2].iter().map(|&id| Member::Reference(id));
+        rec.records.insert(4, Record::ObjectArray(refs.collect()));
+        rec.records.insert(3, int_array(&[5]));
+        rec.root_id = 4;
+
+        assert_eq!(
+            to_object_json(&rec),
+            json!([
+                { "$id": 1, "$values": [10] },
+                { "$ref": 1 },
+                { "$id": 3, "$values": [5] },
+                { "$ref": 3 },
+                // `_items`, which the list only showed up to `_size`
+                { "$id": 2, "$values": [10, 0] },
+            ])
+        );
+    }
+}
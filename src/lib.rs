@@ -0,0 +1,40 @@
+//! Decoder and encoder for the .NET Remoting Binary Format ([MS-NRBF]) used by
+//! `BinaryFormatter`, e.g. for the save games of many Unity games.
+//!
+//! ```no_run
+//! let bytes = std::fs::read("save.dat").unwrap();
+//! let rec = nrbf::parse(&bytes).unwrap();
+//! let root = rec.records[&rec.root_id].as_class();
+//! println!("{}", rec.class_type(root).name);
+//! assert_eq!(nrbf::serialize(&rec), bytes);
+//! ```
+//!
+//! [MS-NRBF]: https://docs.microsoft.com/en-us/openspecs/windows_protocols/ms-nrbf
+
+mod annotate;
+mod dictionary;
+mod diff;
+mod dump;
+#[cfg(test)]
+mod fixtures;
+mod json;
+mod list;
+mod parser;
+mod path;
+mod records;
+mod serializer;
+mod validate;
+
+pub use annotate::annotate;
+pub use dictionary::{
+    DictionaryView, DictionaryViewMut, HashSetView, HashSetViewMut, TypeMismatch,
+};
+pub use diff::Change;
+pub use dump::dump;
+pub use json::{from_json, to_json, to_object_json, JsonError, MAX_OBJECT_DEPTH};
+pub use list::{ListView, ListViewMut};
+pub use parser::{parse, parse_from_reader, ParseError, ParserOptions};
+pub use path::{Location, Path, PathError, Segment, SetValueError, Value, ValueMut};
+pub use records::*;
+pub use serializer::{serialize, serialize_into};
+pub use validate::ValidationError;
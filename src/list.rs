@@ -0,0 +1,431 @@
+use crate::dictionary::{element_type, fits, TypeMismatch};
+use crate::path::{Value, ValueMut};
+use crate::records::*;
+
+/// A `System.Collections.Generic.List<T>`: a class whose `_items` array holds the elements
+/// in its first `_size` slots. Get one with `DeserializedRecord::list`.
+#[derive(Debug, Clone, Copy)]
+pub struct ListView<'a> {
+    rec: &'a DeserializedRecord,
+    info: ListInfo,
+}
+
+/// A mutable `List<T>`. Changes keep `_size` and `_version` up to date and grow `_items`
+/// like .NET does. Get one with `DeserializedRecord::list_mut`.
+#[derive(Debug)]
+pub struct ListViewMut<'a> {
+    rec: &'a mut DeserializedRecord,
+    id: i32,
+    info: ListInfo,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ListInfo {
+    items_id: i32,
+    size_index: usize,
+    version_index: Option<usize>,
+    len: usize,
+}
+
+impl DeserializedRecord {
+    /// View the record with the given id as a list. Returns `None` if it isn't a class with
+    /// an `_items` array and an `Int32` `_size`.
+    pub fn list(&self, id: i32) -> Option<ListView<'_>> {
+        Some(ListView {
+            info: list_info(self, id)?,
+            rec: self,
+        })
+    }
+
+    pub fn list_mut(&mut self, id: i32) -> Option<ListViewMut<'_>> {
+        Some(ListViewMut {
+            info: list_info(self, id)?,
+            rec: self,
+            id,
+        })
+    }
+}
+
+fn list_info(rec: &DeserializedRecord, id: i32) -> Option<ListInfo> {
+    let class = match rec.records.get(&id)? {
+        Record::Class(class) => class,
+        _ => return None,
+    };
+    let class_type = rec.class_type(class);
+    let index = |name| class_type.member_names.iter().position(|n| n == name);
+    let size_index = index("_size")?;
+
+    let items_id = match class.members.get(index("_items")?)? {
+        Member::Reference(id) => *id,
+        _ => return None,
+    };
+    let size = match class.members.get(size_index)? {
+        Member::Primitive(Primitive::Int32(size)) => *size,
+        _ => return None,
+    };
+    let capacity = match rec.records.get(&items_id)? {
+        Record::ObjectArray(vals) | Record::StringArray(vals) => vals.len(),
+        Record::BinaryArray(array) if array.rank() == 1 => array.values.len(),
+        Record::PrimitiveArray(_, vals) => vals.len(),
+        _ => return None,
+    };
+
+    Some(ListInfo {
+        items_id,
+        size_index,
+        version_index: index("_version"),
+        len: (size.max(0) as usize).min(capacity),
+    })
+}
+
+impl<'a> ListView<'a> {
+    pub fn len(&self) -> usize {
+        self.info.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.info.len == 0
+    }
+
+    /// Id of the `_items` array
+    pub fn items_id(&self) -> i32 {
+        self.info.items_id
+    }
+
+    /// Get an element. Elements of object lists are `Value::Member`s, those of lists of
+    /// primitives are `Value::Primitive`s.
+    pub fn get(&self, index: usize) -> Option<Value<'a>> {
+        if index >= self.info.len {
+            return None;
+        }
+        match &self.rec.records[&self.info.items_id] {
+            Record::ObjectArray(vals) | Record::StringArray(vals) => {
+                Some(Value::Member(&vals[index]))
+            }
+            Record::BinaryArray(array) => Some(Value::Member(&array.values[index])),
+            Record::PrimitiveArray(_, vals) => Some(Value::Primitive(&vals[index])),
+            _ => None,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Value<'a>> + 'a {
+        let view = *self;
+        (0..self.info.len).filter_map(move |i| view.get(i))
+    }
+}
+
+impl ListViewMut<'_> {
+    pub fn len(&self) -> usize {
+        self.info.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.info.len == 0
+    }
+
+    pub fn items_id(&self) -> i32 {
+        self.info.items_id
+    }
+
+    pub fn as_view(&self) -> ListView<'_> {
+        ListView {
+            rec: self.rec,
+            info: self.info,
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<Value<'_>> {
+        self.as_view().get(index)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<ValueMut<'_>> {
+        if index >= self.info.len {
+            return None;
+        }
+        match self.items_mut() {
+            Items::Members(vals, _) => Some(ValueMut::Member(&mut vals[index])),
+            Items::Primitives(_, vals) => Some(ValueMut::Primitive(&mut vals[index])),
+        }
+    }
+
+    /// Append an element, growing `_items` if it is full. Fails if `val` doesn't fit the
+    /// element type of `_items`.
+    pub fn push(&mut self, val: Member) -> Result<(), TypeMismatch> {
+        self.insert(self.info.len, val)
+    }
+
+    /// Insert an element at `index`, shifting all elements after it. Fails if `val` doesn't
+    /// fit the element type of `_items`.
+    ///
+    /// Panics if `index > len`.
+    pub fn insert(&mut self, index: usize, val: Member) -> Result<(), TypeMismatch> {
+        let len = self.info.len;
+        assert!(
+            index <= len,
+            "Index {} out of bounds for list of length {}",
+            index,
+            len
+        );
+        let expected = element_type(&self.rec.records[&self.info.items_id]);
+        if !fits(&expected, &val) {
+            return Err(TypeMismatch {
+                value: val,
+                expected,
+            });
+        }
+        match self.items_mut() {
+            Items::Members(vals, lengths) => {
+                insert_item(vals, len, index, val, Member::Null);
+                if let Some(lengths) = lengths {
+                    lengths[0] = vals.len() as i32;
+                }
+                self.rec.reinline(self.info.items_id, &[]);
+            }
+            Items::Primitives(typ, vals) => {
+                let val = match val {
+                    Member::Primitive(val) => val,
+                    _ => unreachable!("fits checked the type of val"),
+                };
+                let empty = default_primitive(typ);
+                insert_item(vals, len, index, val, empty);
+            }
+        }
+        self.set_len(len + 1);
+        Ok(())
+    }
+
+    /// Remove and return the element at `index`, shifting all elements after it.
+    ///
+    /// Panics if `index >= len`.
+    pub fn remove(&mut self, index: usize) -> Member {
+        let len = self.info.len;
+        assert!(
+            index < len,
+            "Index {} out of bounds for list of length {}",
+            index,
+            len
+        );
+        let removed = match self.items_mut() {
+            Items::Members(vals, _) => {
+                let removed = remove_item(vals, len, index, Member::Null);
+                self.rec.reinline(self.info.items_id, &[]);
+                removed
+            }
+            Items::Primitives(typ, vals) => {
+                let empty = default_primitive(typ);
+                Member::Primitive(remove_item(vals, len, index, empty))
+            }
+        };
+        self.set_len(len - 1);
+        removed
+    }
+
+    /// Shorten the list to `len` elements. Does nothing if it isn't longer than that.
+    pub fn truncate(&mut self, len: usize) {
+        let old_len = self.info.len;
+        if len >= old_len {
+            return;
+        }
+        match self.items_mut() {
+            Items::Members(vals, _) => {
+                vals[len..old_len]
+                    .iter_mut()
+                    .for_each(|v| *v = Member::Null);
+                self.rec.reinline(self.info.items_id, &[]);
+            }
+            Items::Primitives(typ, vals) => {
+                let empty = default_primitive(typ);
+                vals[len..old_len]
+                    .iter_mut()
+                    .for_each(|v| *v = empty.clone());
+            }
+        }
+        self.set_len(len);
+    }
+
+    fn items_mut(&mut self) -> Items<'_> {
+        match self.rec.records.get_mut(&self.info.items_id).unwrap() {
+            Record::ObjectArray(vals) | Record::StringArray(vals) => Items::Members(vals, None),
+            Record::BinaryArray(array) => {
+                Items::Members(&mut array.values, Some(&mut array.lengths))
+            }
+            Record::PrimitiveArray(typ, vals) => Items::Primitives(typ, vals),
+            _ => unreachable!("list_info checked the type of _items"),
+        }
+    }
+
+    /// Update `_size` and bump `_version`, like every modification in .NET does.
+    fn set_len(&mut self, len: usize) {
+        self.info.len = len;
+        let class = self.rec.records.get_mut(&self.id).unwrap().as_class_mut();
+        class.members[self.info.size_index] = Member::Primitive(Primitive::Int32(len as i32));
+        if let Some(index) = self.info.version_index {
+            if let Member::Primitive(Primitive::Int32(version)) = &mut class.members[index] {
+                *version = version.wrapping_add(1);
+            }
+        }
+    }
+}
+
+enum Items<'a> {
+    /// The values and, for a `BinaryArray`, its lengths
+    Members(&'a mut Vec<Member>, Option<&'a mut Vec<i32>>),
+    Primitives(&'a PrimitiveType, &'a mut Vec<Primitive>),
+}
+
+/// Insert into the used part of `vals`, reusing a free slot or doubling the capacity
+/// (to at least 4) if there is none.
+fn insert_item<T: Clone>(vals: &mut Vec<T>, len: usize, index: usize, val: T, empty: T) {
+    if len < vals.len() {
+        vals.pop();
+        vals.insert(index, val);
+    } else {
+        let capacity = (vals.len() * 2).max(4);
+        vals.insert(index, val);
+        vals.resize(capacity, empty);
+    }
+}
+
+/// Remove from the used part of `vals`, clearing the slot that becomes free.
+fn remove_item<T>(vals: &mut Vec<T>, len: usize, index: usize, empty: T) -> T {
+    let removed = vals.remove(index);
+    vals.insert(len - 1, empty);
+    removed
+}
+
+/// The value .NET uses for unused slots of an array of primitives.
+fn default_primitive(typ: &PrimitiveType) -> Primitive {
+    match typ {
+        PrimitiveType::Boolean => Primitive::Boolean(false),
+        PrimitiveType::Byte => Primitive::Byte(0),
+        PrimitiveType::Char => Primitive::Char('\0'),
+        PrimitiveType::Decimal => Primitive::Decimal("0".into()),
+        PrimitiveType::Double => Primitive::Double(0.0),
+        PrimitiveType::Int16 => Primitive::Int16(0),
+        PrimitiveType::Int32 => Primitive::Int32(0),
+        PrimitiveType::Int64 => Primitive::Int64(0),
+        PrimitiveType::Int8 => Primitive::Int8(0),
+        PrimitiveType::Single => Primitive::Single(0.0),
+        PrimitiveType::TimeSpan => Primitive::TimeSpan(0),
+        PrimitiveType::DateTime => Primitive::DateTime(0),
+        PrimitiveType::UInt16 => Primitive::UInt16(0),
+        PrimitiveType::UInt32 => Primitive::UInt32(0),
+        PrimitiveType::UInt64 => Primitive::UInt64(0),
+        PrimitiveType::Null => Primitive::Null,
+        PrimitiveType::String => Primitive::String(String::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{int, int_list, sample_stream};
+    use crate::{parse, serialize};
+
+    fn contents(rec: &DeserializedRecord) -> (Vec<String>, usize, String) {
+        let list = rec.list(1).unwrap();
+        let items = list
+            .iter()
+            .map(|v| match v {
+                Value::Primitive(p) => p.to_string(),
+                _ => unreachable!(),
+            })
+            .collect();
+        let class = rec.records[&1].as_class();
+        let capacity = match &rec.records[&2] {
+            Record::PrimitiveArray(_, vals) => vals.len(),
+            _ => unreachable!(),
+        };
+        (
+            items,
+            capacity,
+            format!("{:?}", class.members[1..].to_vec()),
+        )
+    }
+
+    #[test]
+    fn modify() {
+        let mut rec = int_list();
+        let mut list = rec.list_mut(1).unwrap();
+        list.push(int(11)).unwrap();
+        list.push(int(12)).unwrap();
+        list.insert(0, int(9)).unwrap();
+        assert_eq!(list.len(), 4);
+
+        let (items, capacity, members) = contents(&rec);
+        assert_eq!(items, ["9", "10", "11", "12"]);
+        assert_eq!(capacity, 4);
+        assert_eq!(members, "[Primitive(Int32(4)), Primitive(Int32(10))]");
+
+        let mut list = rec.list_mut(1).unwrap();
+        assert!(matches!(
+            list.remove(1),
+            Member::Primitive(Primitive::Int32(10))
+        ));
+        list.push(int(13)).unwrap();
+        list.push(int(14)).unwrap();
+        list.truncate(2);
+        list.truncate(3);
+        assert!(list.get(2).is_none());
+
+        let (items, capacity, members) = contents(&rec);
+        assert_eq!(items, ["9", "11"]);
+        assert_eq!(capacity, 8);
+        assert_eq!(members, "[Primitive(Int32(2)), Primitive(Int32(14))]");
+        match &rec.records[&2] {
+            Record::PrimitiveArray(_, vals) => {
+                assert!(vals[2..].iter().all(|v| v.to_string() == "0"))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn wrong_type() {
+        let mut rec = int_list();
+        let mut list = rec.list_mut(1).unwrap();
+        let err = list.push(Member::Null).unwrap_err();
+        assert!(matches!(err.value, Member::Null));
+        assert!(list.insert(0, Member::Reference(2)).is_err());
+        assert!(list.push(Member::Primitive(Primitive::Int64(11))).is_err());
+        assert_eq!(contents(&rec).0, ["10"]);
+
+        // Lists of strings and classes hold references
+        let strings = Record::StringArray(vec![Member::Reference(3), Member::Null]);
+        let classes = Record::BinaryArray(BinaryArray {
+            array_type: BinaryArrayType::Single,
+            lengths: vec![2],
+            lower_bounds: Vec::new(),
+            member_type: MemberType::Class("Item".into(), 4),
+            values: vec![Member::Reference(3), Member::Null],
+        });
+        for items in &[strings, classes] {
+            rec.records.insert(2, items.clone());
+            rec.records.insert(3, Record::String("a".into()));
+            let mut list = rec.list_mut(1).unwrap();
+            assert!(list.push(int(1)).is_err());
+            list.push(Member::Reference(3)).unwrap();
+            assert_eq!(list.len(), 2);
+            list.truncate(1);
+        }
+
+        // Lists of objects also hold boxed primitives
+        let mut rec = parse(&sample_stream()).unwrap();
+        rec.list_mut(4).unwrap().push(int(1)).unwrap();
+    }
+
+    #[test]
+    fn truncate_inlined_items() {
+        // `items` is list 4 whose first item, record 8, is inlined into its `_items`
+        let mut rec = parse(&sample_stream()).unwrap();
+        assert_eq!(rec.layout.inlined[&(5, 0)], [8]);
+
+        rec.list_mut(4).unwrap().truncate(0);
+        assert!(!rec.layout.inlined.contains_key(&(5, 0)));
+
+        let bytes = serialize(&rec);
+        let reparsed = parse(&bytes).unwrap();
+        assert!(reparsed.list(4).unwrap().is_empty());
+        assert_eq!(serialize(&reparsed), bytes);
+    }
+}
@@ -1,171 +1,415 @@
 use std::collections::HashSet;
-use std::io::Write;
 
-mod parser;
-mod records;
-mod serializer;
+use nrbf::{DeserializedRecord, Location, Path};
 
-use records::*;
+mod catalog;
+mod upgrades;
 
 fn main() {
+    let file_arg = clap::Arg::with_name("FILE")
+        .help("The save file to edit")
+        .required(true);
+    let upgrade_arg = clap::Arg::with_name("upgrade")
+        .long("upgrade")
+        .short("u")
+        .help("An upgrade, e.g. Hero_Trait_Giant or Mountain")
+        .takes_value(true)
+        .validator(|s| match catalog::find(&s) {
+            Some(_) => Ok(()),
+            None => Err(format!("Unknown upgrade: '{}'", s)),
+        })
+        .multiple(true)
+        .number_of_values(1)
+        .required_unless("all");
+    let level_arg = clap::Arg::with_name("level")
+        .long("level")
+        .short("l")
+        .takes_value(true)
+        .validator(|s| match s.parse::<i32>() {
+            Ok(level) if level >= 0 => Ok(()),
+            _ => Err(format!("Invalid level: '{}'", s)),
+        });
+    let path_arg = clap::Arg::with_name("PATH")
+        .help("A path like inventory.upgrades._items[3].upgrade.name or inventory.upgrades[*]")
+        .required(true)
+        .validator(|s| s.parse::<Path>().map(|_| ()).map_err(|e| e.to_string()));
+    let all_arg = clap::Arg::with_name("all")
+        .long("all")
+        .conflicts_with("upgrade");
+
     let matches = clap::App::new(clap::crate_name!())
         .version(clap::crate_version!())
         .author(clap::crate_authors!())
-        .arg(
-            clap::Arg::with_name("FILE")
-                .help("The input file to decode")
-                .required(true),
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            clap::SubCommand::with_name("unlock")
+                .about("Unlock upgrades and write the result to FILE.new")
+                .arg(file_arg.clone())
+                .arg(upgrade_arg.clone())
+                .arg(all_arg.clone().help("Unlock all known upgrades"))
+                .arg(
+                    level_arg
+                        .clone()
+                        .help("The level to set the unlocked upgrades to")
+                        .default_value("2"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("remove")
+                .about("Remove upgrades and write the result to FILE.new")
+                .arg(file_arg.clone())
+                .arg(upgrade_arg.clone())
+                .arg(all_arg.clone().help("Remove all upgrades")),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("set-upgrade")
+                .about("Change the level or starting flag of an upgrade and write the result to FILE.new")
+                .arg(file_arg.clone())
+                .arg(
+                    clap::Arg::with_name("UPGRADE")
+                        .help("The upgrade to change, e.g. Hero_Trait_Giant or Mountain")
+                        .required(true)
+                        .validator(|s| match catalog::find(&s) {
+                            Some(_) => Ok(()),
+                            None => Err(format!("Unknown upgrade: '{}'", s)),
+                        }),
+                )
+                .arg(level_arg.clone().help("The new level of the upgrade"))
+                .arg(
+                    clap::Arg::with_name("starting")
+                        .long("starting")
+                        .short("s")
+                        .help("Whether the upgrade can be chosen as a starting upgrade")
+                        .takes_value(true)
+                        .possible_values(&["true", "false"]),
+                )
+                .group(
+                    clap::ArgGroup::with_name("changes")
+                        .args(&["level", "starting"])
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("list-upgrades")
+                .about("List the upgrades in a save and the known upgrades missing from it")
+                .arg(file_arg.clone())
+                .arg(
+                    clap::Arg::with_name("json")
+                        .long("json")
+                        .help("Print the upgrades as JSON instead of a table"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("get")
+                .about("Print the values at a path, e.g. inventory.upgrades._size")
+                .arg(file_arg.clone())
+                .arg(path_arg.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("set")
+                .about("Set the values at a path and write the result to FILE.new")
+                .arg(file_arg.clone())
+                .arg(path_arg.clone())
+                .arg(
+                    clap::Arg::with_name("VALUE")
+                        .help("The new value, parsed according to the type of the target")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("dump")
+                .about("Print the whole save as a tree")
+                .arg(file_arg.clone())
+                .arg(
+                    clap::Arg::with_name("depth")
+                        .short("d")
+                        .long("depth")
+                        .takes_value(true)
+                        .help("Don't print records nested deeper than this")
+                        .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("annotate")
+                .about("Print a hex dump of the save with the records and members marked")
+                .arg(file_arg.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("diff")
+                .about("List the values that were added, removed or changed between two saves")
+                .arg(
+                    clap::Arg::with_name("OLD")
+                        .help("The older save")
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("NEW")
+                        .help("The newer save")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("export")
+                .about("Print the save as JSON that can be turned back into the same save with import")
+                .arg(file_arg.clone())
+                .arg(
+                    clap::Arg::with_name("objects")
+                        .long("objects")
+                        .help("Print a simpler view with nested objects instead, which can't be imported"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("import")
+                .about("Convert JSON created by export back into a save")
+                .arg(
+                    clap::Arg::with_name("JSON")
+                        .help("The JSON file to read")
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("OUTPUT")
+                        .help("Where to write the save")
+                        .required(true),
+                ),
         )
         .get_matches();
 
-    let file = matches.value_of("FILE").unwrap();
-    let bytes = std::fs::read(file).unwrap();
-
-    let mut rec = parser::parse(&bytes).unwrap();
-
-    let mut upgrades_to_add = HashSet::new();
-    upgrades_to_add.insert("Hero_Class_Infantry");
-    upgrades_to_add.insert("Hero_Class_Pikemen");
-    upgrades_to_add.insert("Hero_Class_Archers");
-
-    upgrades_to_add.insert("Hero_Upgrade_PikeCharge");
-    upgrades_to_add.insert("Hero_Upgrade_Plunge_Attack");
-    upgrades_to_add.insert("Hero_Upgrade_ArcheryFocus");
-
-    upgrades_to_add.insert("Hero_Upgrade_Bomb");
-    upgrades_to_add.insert("Hero_Upgrade_Horn");
-    upgrades_to_add.insert("Hero_Upgrade_Warhammer");
-    upgrades_to_add.insert("Hero_Upgrade_Mine");
-    upgrades_to_add.insert("Hero_Upgrade_Size");
-    upgrades_to_add.insert("Hero_Upgrade_Grail");
-    upgrades_to_add.insert("Hero_Upgrade_PhilosophersStone");
-    upgrades_to_add.insert("Hero_Upgrade_Cornucopia");
-
-    upgrades_to_add.insert("Hero_Trait_Sturdy"); // Sure-Footed
-    upgrades_to_add.insert("Hero_Trait_Fast"); // Fleet of Foot
-    upgrades_to_add.insert("Hero_Trait_CheaperSkills"); // Skillful
-    upgrades_to_add.insert("Hero_Trait_SharpWeapons"); // Sharp Weapons
-    upgrades_to_add.insert("Hero_Trait_FastReplenish"); // Rousing Speeches
-    upgrades_to_add.insert("Hero_Trait_CheaperItems"); // Collector
-    upgrades_to_add.insert("Hero_Trait_ExtraArmor"); // Ironskin
-    upgrades_to_add.insert("Hero_Trait_ShortCooldown"); // Energetic
-    upgrades_to_add.insert("Hero_Trait_BluntWeapons"); // Heavy Weapons ?
-    upgrades_to_add.insert("Hero_Trait_ExtraUnit"); // Popular
-    upgrades_to_add.insert("Hero_Trait_ExtraUses"); // Heavy Load
-    upgrades_to_add.insert("Hero_Trait_Giant"); // Mountain
-                                                // Fearless
-
-    let mut upgrade_entries_to_update = Vec::new();
-    let mut upgrade_inners_to_update = Vec::new();
-    let mut upgrade_entry_class_id = None;
-    let mut upgrade_inner_class_id = None;
-
-    let user_save = rec.records[&rec.root_id].as_class();
-    let inventory = rec.class_member_deref(user_save, "inventory").as_class();
-    let upgrades_id = *rec.class_member(inventory, "upgrades").as_reference();
-    let upgrades = rec.records[&upgrades_id].as_class();
-    let length = rec.class_member(upgrades, "_size").as_i32() as usize;
-    let length_index = rec.class_member_index(upgrades, "_size");
-    let items_id = *rec.class_member(upgrades, "_items").as_reference();
-    let items = rec.records[&items_id].as_binary_array();
-
-    for item in &items[..length] {
-        let entry = rec.records[item.as_reference()].as_class();
-        let upgrade = rec.class_member_deref(entry, "upgrade").as_class();
-        let name_id = rec.class_member(upgrade, "name").as_reference();
-        let name = rec.records[name_id].as_string();
-        if !upgrades_to_add.remove(name) {
-            println!("Unknown upgrade: '{}'", name);
-        }
-        if can_be_starting(name) {
-            upgrade_entries_to_update.push(*item.as_reference());
-        }
-        upgrade_inners_to_update.push(*rec.class_member(entry, "upgrade").as_reference());
-        upgrade_entry_class_id = Some(entry.class_type_id);
-        upgrade_inner_class_id = Some(upgrade.class_type_id);
-    }
+    match matches.subcommand() {
+        ("unlock", Some(matches)) => {
+            let file = matches.value_of("FILE").unwrap();
+            let mut rec = read_save(file);
+
+            let upgrades = selected_upgrades(matches);
+            let level = matches.value_of("level").unwrap().parse().unwrap();
+
+            let unlocked = or_exit(upgrades::unlock_upgrades(&mut rec, upgrades, level));
+            for id in &unlocked.unknown {
+                println!("Unknown upgrade: '{}'", id);
+            }
+            for id in &unlocked.updated {
+                println!("Updating {}", describe_upgrade(id));
+            }
+            for id in &unlocked.added {
+                println!("Adding {}", describe_upgrade(id));
+            }
+            write_save(file, &rec);
+        }
+        ("remove", Some(matches)) => {
+            let file = matches.value_of("FILE").unwrap();
+            let mut rec = read_save(file);
+            let upgrades = if matches.is_present("all") {
+                None
+            } else {
+                Some(selected_upgrades(matches))
+            };
+
+            for id in or_exit(upgrades::remove_upgrades(&mut rec, upgrades)) {
+                println!("Removing {}", describe_upgrade(&id));
+            }
+            write_save(file, &rec);
+        }
+        ("set-upgrade", Some(matches)) => {
+            let file = matches.value_of("FILE").unwrap();
+            let upgrade = catalog::find(matches.value_of("UPGRADE").unwrap()).unwrap();
+            let level = matches.value_of("level").map(|s| s.parse().unwrap());
+            let starting = matches.value_of("starting").map(|s| s == "true");
+
+            let mut rec = read_save(file);
+            or_exit(upgrades::set_upgrade(&mut rec, upgrade, level, starting));
+            write_save(file, &rec);
+        }
+        ("list-upgrades", Some(matches)) => {
+            let file = matches.value_of("FILE").unwrap();
+            let rec = read_save(file);
+            let (flag_name, upgrades) = or_exit(upgrades::read_upgrades(&rec));
+            let missing: Vec<_> = catalog::UPGRADES
+                .iter()
+                .filter(|u| upgrades.iter().all(|s| s.id != u.id))
+                .collect();
+
+            if matches.is_present("json") {
+                upgrades::print_upgrades_json(&flag_name, &upgrades, &missing);
+            } else {
+                upgrades::print_upgrades_table(&flag_name, &upgrades, &missing);
+            }
+        }
+        ("get", Some(matches)) => {
+            let file = matches.value_of("FILE").unwrap();
+            let rec = read_save(file);
+
+            for location in select(&rec, matches.value_of("PATH").unwrap()) {
+                println!("{}", rec.format_value(location));
+            }
+        }
+        ("set", Some(matches)) => {
+            let file = matches.value_of("FILE").unwrap();
+            let value = matches.value_of("VALUE").unwrap();
+            let mut rec = read_save(file);
+
+            let locations = select(&rec, matches.value_of("PATH").unwrap());
+            if let Err(err) = rec.set_values(&locations, value) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            write_save(file, &rec);
+        }
+        ("dump", Some(matches)) => {
+            let file = matches.value_of("FILE").unwrap();
+            let rec = read_save(file);
+            let depth = matches.value_of("depth").map(|d| d.parse().unwrap());
 
-    for id in upgrade_entries_to_update {
-        let entry = rec.records[&id].as_class();
-        let is_starting_index = rec.class_member_index(entry, "isStarting");
-        let entry = rec.records.get_mut(&id).unwrap().as_class_mut();
-        entry.members[is_starting_index] = Member::Primitive(Primitive::Boolean(true));
+            let stdout = std::io::stdout();
+            if let Err(err) = nrbf::dump(&rec, &mut stdout.lock(), depth) {
+                eprintln!("Failed to write output: {}", err);
+                std::process::exit(1);
+            }
+        }
+        ("annotate", Some(matches)) => {
+            let file = matches.value_of("FILE").unwrap();
+            let bytes = match std::fs::read(file) {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    eprintln!("Failed to read {}: {}", file, err);
+                    std::process::exit(1);
+                }
+            };
+            let options = nrbf::ParserOptions {
+                track_spans: true,
+                ..Default::default()
+            };
+            let rec = match options.parse(&bytes) {
+                Ok(rec) => rec,
+                Err(err) => {
+                    eprintln!("Failed to parse {}: {}", file, err);
+                    std::process::exit(1);
+                }
+            };
+
+            let stdout = std::io::stdout();
+            if let Err(err) = nrbf::annotate(&rec, &bytes, &mut stdout.lock()) {
+                eprintln!("Failed to write output: {}", err);
+                std::process::exit(1);
+            }
+        }
+        ("diff", Some(matches)) => {
+            let old = read_save(matches.value_of("OLD").unwrap());
+            let new = read_save(matches.value_of("NEW").unwrap());
+
+            for change in old.diff(&new) {
+                println!("{}", change);
+            }
+        }
+        ("export", Some(matches)) => {
+            let rec = read_save(matches.value_of("FILE").unwrap());
+            let json = if matches.is_present("objects") {
+                nrbf::to_object_json(&rec)
+            } else {
+                nrbf::to_json(&rec)
+            };
+            println!("{}", serde_json::to_string_pretty(&json).unwrap());
+        }
+        ("import", Some(matches)) => {
+            let file = matches.value_of("JSON").unwrap();
+            let json = match std::fs::read_to_string(file)
+                .map_err(|e| e.to_string())
+                .and_then(|s| serde_json::from_str(&s).map_err(|e| e.to_string()))
+            {
+                Ok(json) => json,
+                Err(err) => {
+                    eprintln!("Failed to read {}: {}", file, err);
+                    std::process::exit(1);
+                }
+            };
+            let rec = match nrbf::from_json(&json) {
+                Ok(rec) => rec,
+                Err(err) => {
+                    eprintln!("Invalid save in {}: {}", file, err);
+                    std::process::exit(1);
+                }
+            };
+            write_file(matches.value_of("OUTPUT").unwrap(), &rec);
+        }
+        _ => unreachable!(),
     }
+}
 
-    for id in upgrade_inners_to_update {
-        let entry = rec.records[&id].as_class();
-        let level_index = rec.class_member_index(entry, "level");
-        let entry = rec.records.get_mut(&id).unwrap().as_class_mut();
-        entry.members[level_index] = Member::Primitive(Primitive::Int32(2));
+fn selected_upgrades(matches: &clap::ArgMatches) -> HashSet<&'static str> {
+    if matches.is_present("all") {
+        catalog::UPGRADES.iter().map(|u| u.id).collect()
+    } else {
+        matches
+            .values_of("upgrade")
+            .unwrap()
+            .map(|s| catalog::find(s).unwrap().id)
+            .collect()
     }
+}
 
-    rec.records
-        .get_mut(&upgrades_id)
-        .unwrap()
-        .as_class_mut()
-        .members[length_index] =
-        Member::Primitive(Primitive::Int32((length + upgrades_to_add.len()) as i32));
-
-    let mut upgrade_entries_to_add = Vec::new();
-    let mut next_id = rec.records.keys().max().unwrap() + 1;
-
-    for upgrade_name in upgrades_to_add {
-        upgrade_entries_to_add.push(next_id);
-        rec.records.insert(
-            next_id,
-            Record::Class(Class {
-                class_type_id: upgrade_entry_class_id.unwrap(),
-                members: vec![
-                    Member::Reference(next_id + 1),
-                    Member::Primitive(Primitive::Boolean(can_be_starting(upgrade_name))),
-                    Member::Primitive(Primitive::Boolean(true)),
-                ],
-            }),
-        );
-        rec.records.insert(
-            next_id + 1,
-            Record::Class(Class {
-                class_type_id: upgrade_inner_class_id.unwrap(),
-                members: vec![
-                    Member::Reference(next_id + 2),
-                    Member::Primitive(Primitive::Int32(2)),
-                ],
-            }),
-        );
-        rec.records
-            .insert(next_id + 2, Record::String(upgrade_name.into()));
-        next_id += 3;
+fn describe_upgrade(id: &str) -> String {
+    match catalog::by_id(id) {
+        Some(upgrade) => format!("{} ({})", upgrade.name, upgrade.category),
+        None => id.into(),
     }
+}
 
-    let mut next_index = length;
-    let items = rec
-        .records
-        .get_mut(&items_id)
-        .unwrap()
-        .as_binary_array_mut();
+fn or_exit<T>(result: Result<T, upgrades::Error>) -> T {
+    match result {
+        Ok(val) => val,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
 
-    for id in upgrade_entries_to_add {
-        if next_index < items.len() {
-            items[next_index] = Member::Reference(id);
-        } else {
-            items.push(Member::Reference(id));
+fn read_save(file: &str) -> DeserializedRecord {
+    let input = match std::fs::File::open(file) {
+        Ok(input) => input,
+        Err(err) => {
+            eprintln!("Failed to read {}: {}", file, err);
+            std::process::exit(1);
+        }
+    };
+    match nrbf::parse_from_reader(input) {
+        Ok(rec) => rec,
+        Err(err) => {
+            eprintln!("Failed to parse {}: {}", file, err);
+            std::process::exit(1);
         }
-        next_index += 1;
     }
+}
+
+fn write_save(file: &str, rec: &DeserializedRecord) {
+    write_file(&format!("{}.new", file), rec);
+}
+
+fn write_file(file: &str, rec: &DeserializedRecord) {
+    validate_or_exit(rec);
+    let result = std::fs::File::create(file)
+        .and_then(|output| nrbf::serialize_into(rec, std::io::BufWriter::new(output)));
+    if let Err(err) = result {
+        eprintln!("Failed to write {}: {}", file, err);
+        std::process::exit(1);
+    }
+}
 
-    let output = serializer::serialize(&rec);
-    let mut file = std::fs::File::create(format!("{}.new", file)).unwrap();
-    file.write_all(&output).unwrap();
+fn validate_or_exit(rec: &DeserializedRecord) {
+    if let Err(errors) = rec.validate() {
+        for err in errors {
+            eprintln!("{}", err);
+        }
+        eprintln!("Refusing to write an invalid save");
+        std::process::exit(1);
+    }
 }
 
-fn can_be_starting(s: &str) -> bool {
-    !matches!(
-        s,
-        "Hero_Class_Infantry"
-            | "Hero_Class_Pikemen"
-            | "Hero_Class_Archers"
-            | "Hero_Upgrade_PikeCharge"
-            | "Hero_Upgrade_Plunge_Attack"
-            | "Hero_Upgrade_ArcheryFocus"
-    )
+fn select(rec: &DeserializedRecord, path: &str) -> Vec<Location> {
+    match rec.select(&path.parse().unwrap()) {
+        Ok(locations) => locations,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
 }
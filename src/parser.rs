@@ -1,62 +1,302 @@
-use std::collections::HashMap;
-use std::io::{Error, ErrorKind};
+use std::collections::hash_map::{Entry, HashMap};
+use std::fmt;
+use std::io::{self, Read};
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{ByteOrder, LittleEndian};
 
 use super::records::*;
 
-type Result<T = ()> = std::io::Result<T>;
+type Result<T = ()> = std::result::Result<T, ParseError>;
+
+/// Why a stream couldn't be parsed. Every variant carries the byte offset where it happened.
+#[derive(Debug)]
+pub enum ParseError {
+    UnexpectedEof {
+        offset: usize,
+    },
+    InvalidHeader {
+        offset: usize,
+        byte: u8,
+    },
+    UnsupportedVersion {
+        offset: usize,
+        major: i32,
+        minor: i32,
+    },
+    UnknownRecordType {
+        offset: usize,
+        record_type: u8,
+    },
+    UnexpectedMemberRecord {
+        offset: usize,
+        record_type: u8,
+    },
+    BadMemberType {
+        offset: usize,
+        member_type: u8,
+    },
+    BadPrimitiveType {
+        offset: usize,
+        primitive_type: u8,
+    },
+    UnsupportedArrayType {
+        offset: usize,
+        array_type: u8,
+    },
+    InvalidLength {
+        offset: usize,
+        length: i32,
+    },
+    InvalidUtf8 {
+        offset: usize,
+    },
+    DuplicateId {
+        offset: usize,
+        id: i32,
+    },
+    UnknownMetadataId {
+        offset: usize,
+        id: i32,
+    },
+    Io {
+        offset: usize,
+        error: io::Error,
+    },
+    AllocationLimit {
+        offset: usize,
+        limit: usize,
+    },
+    TooDeep {
+        offset: usize,
+        limit: usize,
+    },
+    TooManyRecords {
+        offset: usize,
+        limit: usize,
+    },
+    StringTooLong {
+        offset: usize,
+        length: usize,
+        limit: usize,
+    },
+}
+
+impl ParseError {
+    /// The byte offset in the input at which the error occurred.
+    pub const fn offset(&self) -> usize {
+        match self {
+            ParseError::UnexpectedEof { offset }
+            | ParseError::InvalidHeader { offset, .. }
+            | ParseError::UnsupportedVersion { offset, .. }
+            | ParseError::UnknownRecordType { offset, .. }
+            | ParseError::UnexpectedMemberRecord { offset, .. }
+            | ParseError::BadMemberType { offset, .. }
+            | ParseError::BadPrimitiveType { offset, .. }
+            | ParseError::UnsupportedArrayType { offset, .. }
+            | ParseError::InvalidLength { offset, .. }
+            | ParseError::InvalidUtf8 { offset }
+            | ParseError::DuplicateId { offset, .. }
+            | ParseError::UnknownMetadataId { offset, .. }
+            | ParseError::Io { offset, .. }
+            | ParseError::AllocationLimit { offset, .. }
+            | ParseError::TooDeep { offset, .. }
+            | ParseError::TooManyRecords { offset, .. }
+            | ParseError::StringTooLong { offset, .. } => *offset,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedEof { .. } => write!(f, "Unexpected end of input"),
+            ParseError::InvalidHeader { byte, .. } => {
+                write!(f, "Header byte must be 0 but was {}", byte)
+            }
+            ParseError::UnsupportedVersion { major, minor, .. } => {
+                write!(f, "Unsupported version {}.{} (expected 1.0)", major, minor)
+            }
+            ParseError::UnknownRecordType { record_type, .. } => {
+                write!(f, "Unknown record type: {}", record_type)
+            }
+            ParseError::UnexpectedMemberRecord { record_type, .. } => {
+                write!(f, "Unexpected record type for member: {}", record_type)
+            }
+            ParseError::BadMemberType { member_type, .. } => {
+                write!(f, "Unexpected member type: {}", member_type)
+            }
+            ParseError::BadPrimitiveType { primitive_type, .. } => {
+                write!(f, "Unexpected primitive type: {}", primitive_type)
+            }
+            ParseError::UnsupportedArrayType { array_type, .. } => {
+                write!(f, "Unsupported BinaryArrayType: {}", array_type)
+            }
+            ParseError::InvalidLength { length, .. } => write!(f, "Invalid length: {}", length),
+            ParseError::InvalidUtf8 { .. } => write!(f, "Invalid UTF-8 in string"),
+            ParseError::DuplicateId { id, .. } => write!(f, "Duplicate record with id {}", id),
+            ParseError::UnknownMetadataId { id, .. } => {
+                write!(f, "Reference to unknown class metadata with id {}", id)
+            }
+            ParseError::Io { error, .. } => write!(f, "Failed to read input: {}", error),
+            ParseError::AllocationLimit { limit, .. } => {
+                write!(f, "The records would take up more than {} bytes", limit)
+            }
+            ParseError::TooDeep { limit, .. } => {
+                write!(f, "Records are nested more than {} levels deep", limit)
+            }
+            ParseError::TooManyRecords { limit, .. } => write!(f, "More than {} records", limit),
+            ParseError::StringTooLong { length, limit, .. } => write!(
+                f,
+                "String of {} bytes is longer than the limit of {}",
+                length, limit
+            ),
+        }?;
+        write!(f, " at offset {:#x}", self.offset())
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
 
 const MESSAGE_END: u8 = 11;
 
+/// How much of the input is read ahead at a time.
+const BUFFER_SIZE: usize = 0x2000;
+
+/// The most elements that are allocated up front for a length read from the input.
+/// Longer collections grow as their elements are actually parsed.
+const MAX_PREALLOCATION: usize = 0x1000;
+
+/// Parse a complete MS-NRBF stream, including the header and the message end record.
 pub fn parse(bytes: &[u8]) -> Result<DeserializedRecord> {
-    Parser::new(bytes).parse()
+    ParserOptions::default().parse(bytes)
+}
+
+/// Parse a complete MS-NRBF stream from `reader`, like [`parse`].
+///
+/// The input is read in small chunks as the parser needs it, so the whole stream never has
+/// to be in memory. The reader may be read past the message end record.
+pub fn parse_from_reader<R: Read>(reader: R) -> Result<DeserializedRecord> {
+    ParserOptions::default().parse_from_reader(reader)
 }
 
-struct Parser<'a> {
-    bytes: &'a [u8],
+/// Settings for parsing. `parse` and `parse_from_reader` use the defaults.
+///
+/// The limits protect against untrusted input. Exceeding one is a parse error.
+#[derive(Debug, Clone)]
+pub struct ParserOptions {
+    /// Fill in `DeserializedRecord::spans`
+    pub track_spans: bool,
+    /// Roughly the most memory in bytes that the records may take up. Records, arrays,
+    /// member lists and strings count against it as soon as their length has been read,
+    /// so lengths and runs of nulls can't make the parser allocate more than this.
+    pub max_allocation: usize,
+    /// How deeply records may be inlined into the members of other records
+    pub max_depth: usize,
+    pub max_records: usize,
+    /// The longest string in bytes
+    pub max_string_length: usize,
+}
+
+impl Default for ParserOptions {
+    fn default() -> Self {
+        Self {
+            track_spans: false,
+            max_allocation: 256 << 20,
+            max_depth: 64,
+            max_records: 1_000_000,
+            max_string_length: 16 << 20,
+        }
+    }
+}
+
+impl ParserOptions {
+    pub fn parse(&self, bytes: &[u8]) -> Result<DeserializedRecord> {
+        self.parse_from_reader(bytes)
+    }
+
+    pub fn parse_from_reader<R: Read>(&self, reader: R) -> Result<DeserializedRecord> {
+        Parser::new(reader, self).parse()
+    }
+}
+
+struct Parser<R> {
+    input: R,
+    buf: Box<[u8]>,
+    /// The unread part of the input is `buf[pos..end]`
+    pos: usize,
+    end: usize,
+    /// The offset of `buf[pos]` in the input
+    offset: usize,
     records: HashMap<i32, Record>,
     class_types: Vec<ClassType>,
     class_metadata: HashMap<i32, usize>,
+    layout: Layout,
+    spans: Option<Spans>,
+    options: ParserOptions,
+    /// Bytes counted against `options.max_allocation` so far
+    allocated: usize,
+    /// How many records are being parsed as members of others
+    depth: usize,
 }
 
-impl<'a> Parser<'a> {
-    fn new(bytes: &'a [u8]) -> Parser<'a> {
+impl<R: Read> Parser<R> {
+    fn new(input: R, options: &ParserOptions) -> Self {
         Self {
-            bytes,
+            input,
+            buf: vec![0; BUFFER_SIZE].into_boxed_slice(),
+            pos: 0,
+            end: 0,
+            offset: 0,
             records: HashMap::new(),
             class_types: Vec::new(),
             class_metadata: HashMap::new(),
+            layout: Layout::default(),
+            spans: if options.track_spans {
+                Some(Spans::default())
+            } else {
+                None
+            },
+            options: options.clone(),
+            allocated: 0,
+            depth: 0,
         }
     }
 
     fn parse(mut self) -> Result<DeserializedRecord> {
+        let offset = self.offset();
         let header_magic = self.parse_u8()?;
         if header_magic != 0 {
-            return Err(Error::new(
-                ErrorKind::InvalidData,
-                format!("Header byte must be 0 but was {}", header_magic),
-            ));
+            return Err(ParseError::InvalidHeader {
+                offset,
+                byte: header_magic,
+            });
         }
 
         let root_id = self.parse_i32()?;
         let header_id = self.parse_i32()?;
-        let major_version = self.parse_i32()?;
-        let minor_version = self.parse_i32()?;
-        assert!(
-            major_version == 1,
-            "unsupported major version (!= 1): {}",
-            major_version
-        );
-        assert!(
-            minor_version == 0,
-            "unsupported minor version (!= 0): {}",
-            minor_version
-        );
+        let offset = self.offset();
+        let major = self.parse_i32()?;
+        let minor = self.parse_i32()?;
+        if major != 1 || minor != 0 {
+            return Err(ParseError::UnsupportedVersion {
+                offset,
+                major,
+                minor,
+            });
+        }
 
         while self.peek_byte()? != MESSAGE_END {
+            let offset = self.offset();
             let (id, record) = self.parse_record()?;
-            self.add_record(id, record)?;
+            self.add_record(offset, id, record)?;
+            self.layout.records.push(id);
         }
 
         Ok(DeserializedRecord {
@@ -64,33 +304,54 @@ impl<'a> Parser<'a> {
             header_id,
             records: self.records,
             class_types: self.class_types,
+            layout: self.layout,
+            spans: self.spans,
         })
     }
 
-    fn add_record(&mut self, id: i32, record: Record) -> Result {
-        if self.records.insert(id, record).is_some() {
-            Err(Error::new(
-                ErrorKind::AlreadyExists,
-                format!("Duplicate record with id {}", id),
-            ))
-        } else {
-            Ok(())
+    fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Add a record that started at `offset` and ends at the current offset.
+    fn add_record(&mut self, offset: usize, id: i32, record: Record) -> Result {
+        if self.records.len() >= self.options.max_records {
+            return Err(ParseError::TooManyRecords {
+                offset,
+                limit: self.options.max_records,
+            });
+        }
+        self.allocate::<(i32, Record)>(offset, 1)?;
+        match self.records.entry(id) {
+            Entry::Occupied(_) => Err(ParseError::DuplicateId { offset, id }),
+            Entry::Vacant(entry) => {
+                entry.insert(record);
+                if let Some(spans) = &mut self.spans {
+                    spans.records.insert(id, offset..self.offset);
+                }
+                Ok(())
+            }
         }
     }
 
     fn parse_record(&mut self) -> Result<(i32, Record)> {
+        let offset = self.offset();
         match self.parse_u8()? {
             1 => self.parse_class_with_id(),
+            2 => self.parse_system_class_with_members(),
+            3 => self.parse_class_with_members(),
             4 => self.parse_system_class_with_members_and_type(),
             5 => self.parse_class_with_members_and_type(),
             6 => self.parse_binary_object_string(),
             7 => self.parse_binary_array(),
             12 => self.parse_binary_library(),
             15 => self.parse_array_single_primitive(),
-            other => Err(Error::new(
-                ErrorKind::Other,
-                format!("Unknown record type: {}", other),
-            )),
+            16 => self.parse_array_single_object(),
+            17 => self.parse_array_single_string(),
+            record_type => Err(ParseError::UnknownRecordType {
+                offset,
+                record_type,
+            }),
         }
     }
 
@@ -108,186 +369,354 @@ impl<'a> Parser<'a> {
 
     fn parse_binary_array(&mut self) -> Result<(i32, Record)> {
         let id = self.parse_i32()?;
-        let array_type = self.parse_u8()?;
-        if array_type != 0 {
-            todo!("Array with BinaryArrayType {}", array_type);
-        }
-        let rank = self.parse_i32()?;
-        if rank != 1 {
-            todo!("Array with rank {}", rank);
-        }
-        let length = self.parse_i32()? as usize;
-        let member_type = self.parse_u8()?;
-        let member_type = self.parse_member_type(member_type)?;
-        let mut vals = Vec::with_capacity(length);
-        while vals.len() < length {
-            match self.parse_member(&member_type)? {
-                Member::NullMultiple(count) => {
-                    for _ in 0..count as usize {
-                        vals.push(Member::Null);
-                    }
+        let offset = self.offset();
+        let array_type = match self.parse_u8()? {
+            0 => BinaryArrayType::Single,
+            1 => BinaryArrayType::Jagged,
+            2 => BinaryArrayType::Rectangular,
+            3 => BinaryArrayType::SingleOffset,
+            4 => BinaryArrayType::JaggedOffset,
+            5 => BinaryArrayType::RectangularOffset,
+            array_type => {
+                return Err(ParseError::UnsupportedArrayType { offset, array_type });
+            }
+        };
+        let offset = self.offset();
+        let rank = self.parse_count()?;
+        self.allocate::<i32>(offset, rank.saturating_mul(2))?;
+        let mut lengths = Vec::with_capacity(rank.min(MAX_PREALLOCATION));
+        let offset = self.offset();
+        let mut total_length = 1usize;
+        for _ in 0..rank {
+            let length = self.parse_count()?;
+            total_length = match total_length.checked_mul(length) {
+                Some(total_length) => total_length,
+                None => {
+                    return Err(ParseError::InvalidLength {
+                        offset,
+                        length: length as i32,
+                    })
                 }
-                other => vals.push(other),
+            };
+            lengths.push(length as i32);
+        }
+        self.allocate::<Member>(offset, total_length)?;
+        let mut lower_bounds = Vec::new();
+        if array_type.has_lower_bounds() {
+            for _ in 0..rank {
+                lower_bounds.push(self.parse_i32()?);
             }
         }
-        Ok((id, Record::BinaryArray(member_type, vals)))
+        let type_offset = self.offset();
+        let member_type = self.parse_u8()?;
+        let member_type = self.parse_member_type(member_type, type_offset)?;
+        let values = self.parse_array_members(id, total_length, &member_type)?;
+        let array = BinaryArray {
+            array_type,
+            lengths,
+            lower_bounds,
+            member_type,
+            values,
+        };
+        Ok((id, Record::BinaryArray(array)))
     }
 
     fn parse_array_single_primitive(&mut self) -> Result<(i32, Record)> {
         let id = self.parse_i32()?;
-        let length = self.parse_i32()? as usize;
+        let offset = self.offset();
+        let length = self.parse_count()?;
+        self.allocate::<Primitive>(offset, length)?;
         let typ = self.parse_primitive_type()?;
-        let mut vals = Vec::with_capacity(length);
-        for _ in 0..length {
+        let mut vals = Vec::with_capacity(length.min(MAX_PREALLOCATION));
+        for i in 0..length {
+            let offset = self.offset();
             vals.push(self.parse_primitive(&typ)?);
+            if let Some(spans) = &mut self.spans {
+                spans.members.insert((id, i), offset..self.offset);
+            }
         }
         Ok((id, Record::PrimitiveArray(typ, vals)))
     }
 
+    fn parse_array_single_object(&mut self) -> Result<(i32, Record)> {
+        let id = self.parse_i32()?;
+        let offset = self.offset();
+        let length = self.parse_count()?;
+        self.allocate::<Member>(offset, length)?;
+        let vals = self.parse_array_members(id, length, &MemberType::Object)?;
+        Ok((id, Record::ObjectArray(vals)))
+    }
+
+    fn parse_array_single_string(&mut self) -> Result<(i32, Record)> {
+        let id = self.parse_i32()?;
+        let offset = self.offset();
+        let length = self.parse_count()?;
+        self.allocate::<Member>(offset, length)?;
+        let vals = self.parse_array_members(id, length, &MemberType::String)?;
+        Ok((id, Record::StringArray(vals)))
+    }
+
+    fn parse_array_members(
+        &mut self,
+        parent: i32,
+        length: usize,
+        typ: &MemberType,
+    ) -> Result<Vec<Member>> {
+        let mut vals = Vec::with_capacity(length.min(MAX_PREALLOCATION));
+        while vals.len() < length {
+            self.parse_member_into(parent, &mut vals, length, typ)?;
+        }
+        Ok(vals)
+    }
+
     fn parse_class_with_id(&mut self) -> Result<(i32, Record)> {
         let id = self.parse_i32()?;
+        let offset = self.offset();
         let metadata_id = self.parse_i32()?;
-        let class_type_id = self.class_metadata[&metadata_id];
+        let class_type_id = match self.class_metadata.get(&metadata_id) {
+            Some(class_type_id) => *class_type_id,
+            None => {
+                return Err(ParseError::UnknownMetadataId {
+                    offset,
+                    id: metadata_id,
+                })
+            }
+        };
+        self.layout.class_with_id.insert(id);
         let class_type = &self.class_types[class_type_id];
         let member_types = class_type.member_types.clone();
         let class = Class {
             class_type_id,
-            members: self.parse_members(&member_types)?,
+            members: self.parse_members(id, &member_types)?,
         };
         Ok((id, Record::Class(class)))
     }
 
-    fn parse_system_class_with_members_and_type(&mut self) -> Result<(i32, Record)> {
+    fn parse_system_class_with_members(&mut self) -> Result<(i32, Record)> {
+        let start = self.offset();
         let (id, name, member_names) = self.parse_class_info()?;
-        let member_types = self.parse_member_types(member_names.len())?;
-        let members = self.parse_members(&member_types)?;
+        let class_type = ClassType {
+            name,
+            library_id: 0,
+            system_class: true,
+            has_member_types: false,
+            member_types: vec![MemberType::Object; member_names.len()],
+            member_names,
+        };
+        self.parse_class_with_new_type(id, start, class_type)
+    }
 
-        let class = Class {
-            class_type_id: self.class_types.len(),
-            members,
+    fn parse_class_with_members(&mut self) -> Result<(i32, Record)> {
+        let start = self.offset();
+        let (id, name, member_names) = self.parse_class_info()?;
+        let library_id = self.parse_i32()?;
+        let class_type = ClassType {
+            name,
+            library_id,
+            system_class: false,
+            has_member_types: false,
+            member_types: vec![MemberType::Object; member_names.len()],
+            member_names,
         };
-        self.class_metadata.insert(id, class.class_type_id);
+        self.parse_class_with_new_type(id, start, class_type)
+    }
 
-        self.class_types.push(ClassType {
+    fn parse_system_class_with_members_and_type(&mut self) -> Result<(i32, Record)> {
+        let start = self.offset();
+        let (id, name, member_names) = self.parse_class_info()?;
+        let member_types = self.parse_member_types(member_names.len())?;
+        let class_type = ClassType {
             name,
             library_id: 0,
             system_class: true,
+            has_member_types: true,
             member_names,
             member_types,
-        });
-
-        Ok((id, Record::Class(class)))
+        };
+        self.parse_class_with_new_type(id, start, class_type)
     }
 
     fn parse_class_with_members_and_type(&mut self) -> Result<(i32, Record)> {
+        let start = self.offset();
         let (id, name, member_names) = self.parse_class_info()?;
         let member_types = self.parse_member_types(member_names.len())?;
         let library_id = self.parse_i32()?;
-        let members = self.parse_members(&member_types)?;
-
-        let class = Class {
-            class_type_id: self.class_types.len(),
-            members,
-        };
-        self.class_metadata.insert(id, class.class_type_id);
-
-        self.class_types.push(ClassType {
+        let class_type = ClassType {
             name,
             library_id,
             system_class: false,
+            has_member_types: true,
             member_names,
             member_types,
-        });
+        };
+        self.parse_class_with_new_type(id, start, class_type)
+    }
+
+    /// Parse the members of a class whose metadata started at `start` and was just read.
+    fn parse_class_with_new_type(
+        &mut self,
+        id: i32,
+        start: usize,
+        class_type: ClassType,
+    ) -> Result<(i32, Record)> {
+        if let Some(spans) = &mut self.spans {
+            spans.class_metadata.insert(id, start..self.offset);
+        }
+        let class_type_id = self.class_types.len();
+        let member_types = class_type.member_types.clone();
+        self.class_metadata.insert(id, class_type_id);
+        self.class_types.push(class_type);
+
+        let class = Class {
+            class_type_id,
+            members: self.parse_members(id, &member_types)?,
+        };
 
         Ok((id, Record::Class(class)))
     }
 
-    fn parse_members(&mut self, types: &[MemberType]) -> Result<Vec<Member>> {
+    fn parse_members(&mut self, parent: i32, types: &[MemberType]) -> Result<Vec<Member>> {
+        self.allocate::<Member>(self.offset(), types.len())?;
         let mut result = Vec::with_capacity(types.len());
-        let mut i = 0;
-        while i < types.len() {
-            match self.parse_member(&types[i])? {
-                Member::NullMultiple(count) => {
-                    for _ in 0..count as usize {
-                        result.push(Member::Null);
-                    }
+        while result.len() < types.len() {
+            let typ = &types[result.len()];
+            self.parse_member_into(parent, &mut result, types.len(), typ)?;
+        }
+        Ok(result)
+    }
+
+    /// Parse the next member of `parent` and append it to `members`,
+    /// expanding runs of nulls up to a total of `length` members.
+    fn parse_member_into(
+        &mut self,
+        parent: i32,
+        members: &mut Vec<Member>,
+        length: usize,
+        typ: &MemberType,
+    ) -> Result {
+        let slot = (parent, members.len());
+        let offset = self.offset();
+        let member = self.parse_member(slot, typ)?;
+        if let Some(spans) = &mut self.spans {
+            spans.members.insert(slot, offset..self.offset);
+        }
+        match member {
+            Member::NullMultiple(count) => {
+                if count <= 0 || count as usize > length - members.len() {
+                    return Err(ParseError::InvalidLength {
+                        offset,
+                        length: count,
+                    });
+                }
+                for _ in 0..count as usize {
+                    members.push(Member::Null);
                 }
-                other => result.push(other),
             }
-            i += 1;
+            other => members.push(other),
         }
-        Ok(result)
+        Ok(())
     }
 
-    fn parse_member(&mut self, typ: &MemberType) -> Result<Member> {
+    fn parse_member(&mut self, slot: (i32, usize), typ: &MemberType) -> Result<Member> {
         if let MemberType::Primitive(prim_typ) = typ {
             return Ok(Member::Primitive(self.parse_primitive(prim_typ)?));
         }
-        match self.parse_u8()? {
-            1 => {
-                let (id, record) = self.parse_class_with_id()?;
-                self.add_record(id, record)?;
-                Ok(Member::Reference(id))
-            }
-            4 => {
-                let (id, record) = self.parse_system_class_with_members_and_type()?;
-                self.add_record(id, record)?;
-                Ok(Member::Reference(id))
-            }
-            5 => {
-                let (id, record) = self.parse_class_with_members_and_type()?;
-                self.add_record(id, record)?;
-                Ok(Member::Reference(id))
-            }
-            15 => {
-                let (id, record) = self.parse_array_single_primitive()?;
-                self.add_record(id, record)?;
-                Ok(Member::Reference(id))
-            }
-            6 => {
-                let (id, record) = self.parse_binary_object_string()?;
-                self.add_record(id, record)?;
-                Ok(Member::Reference(id))
-            }
-            7 => {
-                let (id, record) = self.parse_binary_array()?;
-                self.add_record(id, record)?;
-                Ok(Member::Reference(id))
-            }
-            9 => Ok(Member::Reference(self.parse_i32()?)),
-            10 => Ok(Member::Null),
-            14 => Ok(Member::NullMultiple(self.parse_i32()?)),
-            13 => Ok(Member::NullMultiple(self.parse_u8()? as i32)),
-            other => Err(Error::new(
-                ErrorKind::InvalidData,
-                format!("Unexpected record type for member: {}", other),
-            )),
+        if self.depth >= self.options.max_depth {
+            return Err(ParseError::TooDeep {
+                offset: self.offset(),
+                limit: self.options.max_depth,
+            });
         }
+        self.depth += 1;
+        let member = self.parse_member_record(slot);
+        self.depth -= 1;
+        member
+    }
+
+    /// Parse a member that isn't of a primitive type. It can be a record of its own.
+    fn parse_member_record(&mut self, slot: (i32, usize)) -> Result<Member> {
+        let mut inlined = Vec::new();
+        let member = loop {
+            let offset = self.offset();
+            let (id, record) = match self.parse_u8()? {
+                1 => self.parse_class_with_id()?,
+                2 => self.parse_system_class_with_members()?,
+                3 => self.parse_class_with_members()?,
+                4 => self.parse_system_class_with_members_and_type()?,
+                5 => self.parse_class_with_members_and_type()?,
+                15 => self.parse_array_single_primitive()?,
+                16 => self.parse_array_single_object()?,
+                17 => self.parse_array_single_string()?,
+                6 => self.parse_binary_object_string()?,
+                7 => self.parse_binary_array()?,
+                8 => {
+                    let typ = self.parse_primitive_type()?;
+                    break Member::Primitive(self.parse_primitive(&typ)?);
+                }
+                9 => break Member::Reference(self.parse_i32()?),
+                10 => break Member::Null,
+                12 => {
+                    // Libraries can precede the record that uses them, even inside of members
+                    let (id, record) = self.parse_binary_library()?;
+                    self.add_record(offset, id, record)?;
+                    inlined.push(id);
+                    continue;
+                }
+                14 => {
+                    let count = self.parse_i32()?;
+                    self.layout.null_runs.insert(slot, NullRun::Wide(count));
+                    break Member::NullMultiple(count);
+                }
+                13 => {
+                    let count = self.parse_u8()?;
+                    self.layout.null_runs.insert(slot, NullRun::Short(count));
+                    break Member::NullMultiple(count as i32);
+                }
+                record_type => {
+                    return Err(ParseError::UnexpectedMemberRecord {
+                        offset,
+                        record_type,
+                    })
+                }
+            };
+            self.add_record(offset, id, record)?;
+            inlined.push(id);
+            self.layout.inlined.insert(slot, inlined);
+            return Ok(Member::Reference(id));
+        };
+        // Libraries in front of a member that isn't a record don't belong to it
+        self.layout.records.extend(inlined);
+        Ok(member)
     }
 
     fn parse_primitive(&mut self, typ: &PrimitiveType) -> Result<Primitive> {
         Ok(match typ {
             PrimitiveType::Boolean => Primitive::Boolean(self.parse_u8()? != 0),
             PrimitiveType::Byte => Primitive::Byte(self.parse_u8()?),
-            PrimitiveType::Char => Primitive::Char(todo!("primitive char")),
+            PrimitiveType::Char => Primitive::Char(self.parse_char()?),
             PrimitiveType::Decimal => Primitive::Decimal(self.parse_string()?),
-            PrimitiveType::Double => Primitive::Double(self.bytes.read_f64::<LittleEndian>()?),
-            PrimitiveType::Int16 => Primitive::Int16(self.bytes.read_i16::<LittleEndian>()?),
-            PrimitiveType::Int32 => Primitive::Int32(self.bytes.read_i32::<LittleEndian>()?),
-            PrimitiveType::Int64 => Primitive::Int64(self.bytes.read_i64::<LittleEndian>()?),
-            PrimitiveType::Int8 => Primitive::Int8(self.bytes.read_i8()?),
-            PrimitiveType::Single => Primitive::Single(self.bytes.read_f32::<LittleEndian>()?),
-            PrimitiveType::TimeSpan => Primitive::TimeSpan(self.bytes.read_i64::<LittleEndian>()?),
-            PrimitiveType::DateTime => Primitive::DateTime(self.bytes.read_i64::<LittleEndian>()?),
-            PrimitiveType::UInt16 => Primitive::UInt16(self.bytes.read_u16::<LittleEndian>()?),
-            PrimitiveType::UInt32 => Primitive::UInt32(self.bytes.read_u32::<LittleEndian>()?),
-            PrimitiveType::UInt64 => Primitive::UInt64(self.bytes.read_u64::<LittleEndian>()?),
+            PrimitiveType::Double => Primitive::Double(LittleEndian::read_f64(self.take_bytes(8)?)),
+            PrimitiveType::Int16 => Primitive::Int16(LittleEndian::read_i16(self.take_bytes(2)?)),
+            PrimitiveType::Int32 => Primitive::Int32(self.parse_i32()?),
+            PrimitiveType::Int64 => Primitive::Int64(LittleEndian::read_i64(self.take_bytes(8)?)),
+            PrimitiveType::Int8 => Primitive::Int8(self.parse_u8()? as i8),
+            PrimitiveType::Single => Primitive::Single(LittleEndian::read_f32(self.take_bytes(4)?)),
+            PrimitiveType::TimeSpan => {
+                Primitive::TimeSpan(LittleEndian::read_i64(self.take_bytes(8)?))
+            }
+            PrimitiveType::DateTime => {
+                Primitive::DateTime(LittleEndian::read_i64(self.take_bytes(8)?))
+            }
+            PrimitiveType::UInt16 => Primitive::UInt16(LittleEndian::read_u16(self.take_bytes(2)?)),
+            PrimitiveType::UInt32 => Primitive::UInt32(LittleEndian::read_u32(self.take_bytes(4)?)),
+            PrimitiveType::UInt64 => Primitive::UInt64(LittleEndian::read_u64(self.take_bytes(8)?)),
             PrimitiveType::Null => Primitive::Null,
             PrimitiveType::String => Primitive::String(self.parse_string()?),
         })
     }
 
     fn parse_primitive_type(&mut self) -> Result<PrimitiveType> {
+        let offset = self.offset();
         Ok(match self.parse_u8()? {
             1 => PrimitiveType::Boolean,
             2 => PrimitiveType::Byte,
@@ -307,24 +736,26 @@ impl<'a> Parser<'a> {
             16 => PrimitiveType::UInt64,
             17 => PrimitiveType::Null,
             18 => PrimitiveType::String,
-            other => {
-                return Err(Error::new(
-                    ErrorKind::InvalidData,
-                    format!("Unexpected primitive type: {}", other),
-                ))
+            primitive_type => {
+                return Err(ParseError::BadPrimitiveType {
+                    offset,
+                    primitive_type,
+                })
             }
         })
     }
 
     fn parse_member_types(&mut self, count: usize) -> Result<Vec<MemberType>> {
         let mut result = Vec::with_capacity(count);
-        for &t in self.take_bytes(count)? {
-            result.push(self.parse_member_type(t)?);
+        let offset = self.offset();
+        for (i, t) in self.read_vec(count)?.into_iter().enumerate() {
+            result.push(self.parse_member_type(t, offset + i)?);
         }
         Ok(result)
     }
 
-    fn parse_member_type(&mut self, typ: u8) -> Result<MemberType> {
+    /// Parse the additional info of the member type `typ`, whose byte is at `offset`.
+    fn parse_member_type(&mut self, typ: u8, offset: usize) -> Result<MemberType> {
         Ok(match typ {
             0 => MemberType::Primitive(self.parse_primitive_type()?),
             1 => MemberType::String,
@@ -334,11 +765,11 @@ impl<'a> Parser<'a> {
             5 => MemberType::ObjectArray,
             6 => MemberType::StringArray,
             7 => MemberType::PrimitiveArray(self.parse_primitive_type()?),
-            other => {
-                return Err(Error::new(
-                    ErrorKind::InvalidData,
-                    format!("Unexpected member type: {}", other),
-                ))
+            member_type => {
+                return Err(ParseError::BadMemberType {
+                    offset,
+                    member_type,
+                })
             }
         })
     }
@@ -346,8 +777,10 @@ impl<'a> Parser<'a> {
     fn parse_class_info(&mut self) -> Result<(i32, String, Vec<String>)> {
         let id = self.parse_i32()?;
         let name = self.parse_string()?;
-        let member_count = self.parse_i32()? as usize;
-        let mut members = Vec::with_capacity(member_count);
+        let offset = self.offset();
+        let member_count = self.parse_count()?;
+        self.allocate::<(String, MemberType)>(offset, member_count)?;
+        let mut members = Vec::with_capacity(member_count.min(MAX_PREALLOCATION));
         for _ in 0..member_count {
             members.push(self.parse_string()?);
         }
@@ -355,9 +788,40 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_string(&mut self) -> Result<String> {
-        let length = self.parse_length()?;
-        let bytes = self.take_bytes(length as usize)?;
-        Ok(std::str::from_utf8(bytes).unwrap().into())
+        let offset = self.offset();
+        let length = self.parse_length()? as usize;
+        if length > self.options.max_string_length {
+            return Err(ParseError::StringTooLong {
+                offset,
+                length,
+                limit: self.options.max_string_length,
+            });
+        }
+        self.allocate::<u8>(offset, length)?;
+        let offset = self.offset();
+        let bytes = self.read_vec(length)?;
+        String::from_utf8(bytes).map_err(|_| ParseError::InvalidUtf8 { offset })
+    }
+
+    /// Parse a UTF-8 encoded .NET `char`. Since a .NET `char` is a single UTF-16 code unit,
+    /// only sequences of up to three bytes are valid. Surrogates are rejected like any
+    /// other invalid UTF-8.
+    fn parse_char(&mut self) -> Result<char> {
+        let offset = self.offset();
+        let length = match self.peek_byte()? {
+            0x00..=0x7F => 1,
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            _ => return Err(ParseError::InvalidUtf8 { offset }),
+        };
+        let bytes = self.take_bytes(length)?;
+        match std::str::from_utf8(bytes)
+            .ok()
+            .and_then(|s| s.chars().next())
+        {
+            Some(c) => Ok(c),
+            None => Err(ParseError::InvalidUtf8 { offset }),
+        }
     }
 
     fn parse_length(&mut self) -> Result<u32> {
@@ -372,28 +836,346 @@ impl<'a> Parser<'a> {
         Ok(length)
     }
 
-    fn peek_byte(&self) -> Result<u8> {
-        match self.bytes.get(0) {
-            Some(x) => Ok(*x),
-            None => Err(ErrorKind::UnexpectedEof.into()),
+    /// Count `count` values of type `T`, whose length was read at `offset`,
+    /// against `max_allocation` before allocating them.
+    fn allocate<T>(&mut self, offset: usize, count: usize) -> Result {
+        let size = count.saturating_mul(std::mem::size_of::<T>());
+        self.allocated = self.allocated.saturating_add(size);
+        if self.allocated > self.options.max_allocation {
+            return Err(ParseError::AllocationLimit {
+                offset,
+                limit: self.options.max_allocation,
+            });
         }
+        Ok(())
     }
 
-    fn take_bytes<'b>(&'b mut self, n: usize) -> Result<&'a [u8]> {
-        if self.bytes.len() < n {
-            return Err(ErrorKind::UnexpectedEof.into());
+    /// Parse an `i32` that is used as a number of elements and must not be negative.
+    fn parse_count(&mut self) -> Result<usize> {
+        let offset = self.offset();
+        let length = self.parse_i32()?;
+        if length < 0 {
+            Err(ParseError::InvalidLength { offset, length })
+        } else {
+            Ok(length as usize)
         }
+    }
+
+    fn peek_byte(&mut self) -> Result<u8> {
+        self.fill(1)?;
+        Ok(self.buf[self.pos])
+    }
+
+    /// Make sure that at least `n` bytes are buffered. `n` must not exceed `BUFFER_SIZE`.
+    fn fill(&mut self, n: usize) -> Result {
+        if self.end - self.pos >= n {
+            return Ok(());
+        }
+        self.buf.copy_within(self.pos..self.end, 0);
+        self.end -= self.pos;
+        self.pos = 0;
+        while self.end < n {
+            match self.input.read(&mut self.buf[self.end..]) {
+                Ok(0) => {
+                    return Err(ParseError::UnexpectedEof {
+                        offset: self.offset,
+                    })
+                }
+                Ok(read) => self.end += read,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => (),
+                Err(error) => {
+                    return Err(ParseError::Io {
+                        offset: self.offset,
+                        error,
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let result = &self.bytes[..n];
-        self.bytes = &self.bytes[n..];
+    /// Take `n` bytes, where `n` is a small constant.
+    fn take_bytes(&mut self, n: usize) -> Result<&[u8]> {
+        self.fill(n)?;
+        let start = self.pos;
+        self.pos += n;
+        self.offset += n;
+        Ok(&self.buf[start..self.pos])
+    }
+
+    /// Take `n` bytes, where `n` was read from the input. Memory is only allocated as
+    /// the bytes actually arrive, so a bogus length can't exhaust it.
+    fn read_vec(&mut self, n: usize) -> Result<Vec<u8>> {
+        let offset = self.offset;
+        let buffered = n.min(self.end - self.pos);
+        let mut result = Vec::with_capacity(buffered);
+        result.extend_from_slice(&self.buf[self.pos..self.pos + buffered]);
+        self.pos += buffered;
+        if result.len() < n {
+            let rest = (n - result.len()) as u64;
+            if let Err(error) = (&mut self.input).take(rest).read_to_end(&mut result) {
+                return Err(ParseError::Io { offset, error });
+            }
+            if result.len() < n {
+                return Err(ParseError::UnexpectedEof { offset });
+            }
+        }
+        self.offset += n;
         Ok(result)
     }
 
     fn parse_u8(&mut self) -> Result<u8> {
-        self.bytes.read_u8()
+        Ok(self.take_bytes(1)?[0])
     }
 
     fn parse_i32(&mut self) -> Result<i32> {
-        self.bytes.read_i32::<LittleEndian>()
+        Ok(LittleEndian::read_i32(self.take_bytes(4)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{char_array, push_i32, push_string, sample_stream, stream};
+    use crate::serializer::serialize;
+
+    /// Hands out one byte per read, so that every buffer refill is exercised.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match (self.0.split_first(), buf.first_mut()) {
+                (Some((&byte, rest)), Some(out)) => {
+                    *out = byte;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn streaming() {
+        let bytes = sample_stream();
+        let mut rec = parse_from_reader(Trickle(&bytes)).unwrap();
+        assert_eq!(serialize(&rec), bytes);
+
+        let truncated = Trickle(&bytes[..bytes.len() - 1]);
+        match parse_from_reader(truncated) {
+            Err(ParseError::UnexpectedEof { offset }) => assert_eq!(offset, bytes.len() - 1),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+
+        // Longer than the parser's buffer
+        for record in rec.records.values_mut() {
+            if let Record::String(s) = record {
+                *s = "long".repeat(10_000);
+            }
+        }
+        let bytes = serialize(&rec);
+        let rec = parse_from_reader(Trickle(&bytes)).unwrap();
+        assert_eq!(serialize(&rec), bytes);
+    }
+
+    #[test]
+    fn spans() {
+        let options = ParserOptions {
+            track_spans: true,
+            ..Default::default()
+        };
+        let rec = options.parse(&sample_stream()).unwrap();
+        let spans = rec.spans.as_ref().unwrap();
+
+        assert_eq!(spans.records.len(), rec.records.len());
+        for (&(parent, _), span) in &spans.members {
+            let record = &spans.records[&parent];
+            assert!(record.start < span.start && span.end <= record.end);
+        }
+        for (id, span) in &spans.class_metadata {
+            assert_eq!(spans.records[id].start + 1, span.start);
+        }
+        // The inlined string is the whole first member of the root
+        assert_eq!(spans.members[&(1, 0)], spans.records[&3]);
+    }
+
+    #[test]
+    fn invalid_chars() {
+        let invalid: &[&[u8]] = &[
+            &[0x80],                   // lone continuation byte
+            &[0xC0, 0x80],             // overlong encoding
+            &[0xE2, 0x82, 0x41],       // bad continuation byte
+            &[0xED, 0xA0, 0x80],       // surrogate
+            &[0xF0, 0x9F, 0x98, 0x80], // outside the BMP
+        ];
+        for encoded in invalid {
+            match parse(&char_array(encoded, 1)) {
+                Err(ParseError::InvalidUtf8 { offset: 27 }) => (),
+                other => panic!("{:x?}: {:?}", encoded, other),
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_char() {
+        let mut bytes = char_array(&[0xE2, 0x82], 1);
+        bytes.pop();
+        match parse(&bytes) {
+            Err(ParseError::UnexpectedEof { .. }) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn assert_round_trip(records: &[u8]) -> DeserializedRecord {
+        let bytes = stream(records);
+        let rec = parse(&bytes).unwrap();
+        assert_eq!(serialize(&rec), bytes);
+        rec
+    }
+
+    /// A class with a single member `value` without member types, so it's an object.
+    fn class_info(b: &mut Vec<u8>, record_type: u8, id: i32, name: &str) {
+        b.push(record_type);
+        push_i32(b, id);
+        push_string(b, name);
+        push_i32(b, 1);
+        push_string(b, "value");
+    }
+
+    #[test]
+    fn system_class_with_members() {
+        let mut b = Vec::new();
+        class_info(&mut b, 2, 1, "System.Tuple");
+        b.push(10);
+        let rec = assert_round_trip(&b);
+        let class_type = &rec.class_types[0];
+        assert!(class_type.system_class && !class_type.has_member_types);
+    }
+
+    #[test]
+    fn class_with_members() {
+        let mut b = vec![12];
+        push_i32(&mut b, 2);
+        push_string(&mut b, "Assembly");
+        class_info(&mut b, 3, 1, "Save");
+        push_i32(&mut b, 2);
+        b.push(6);
+        push_i32(&mut b, 3);
+        push_string(&mut b, "save");
+        let rec = assert_round_trip(&b);
+        let class_type = &rec.class_types[0];
+        assert!(!class_type.system_class && !class_type.has_member_types);
+        assert_eq!(class_type.library_id, 2);
+    }
+
+    #[test]
+    fn member_primitive_typed() {
+        let mut b = Vec::new();
+        class_info(&mut b, 2, 1, "System.Tuple");
+        b.extend(&[8, 8]);
+        push_i32(&mut b, 42);
+        let rec = assert_round_trip(&b);
+        match &rec.records[&1] {
+            Record::Class(class) => {
+                assert!(matches!(
+                    class.members[0],
+                    Member::Primitive(Primitive::Int32(42))
+                ))
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn array_single_string() {
+        let mut b = vec![17];
+        push_i32(&mut b, 1);
+        push_i32(&mut b, 4);
+        b.push(6);
+        push_i32(&mut b, 2);
+        push_string(&mut b, "a");
+        b.push(9);
+        push_i32(&mut b, 2);
+        b.extend(&[13, 2]);
+        let rec = assert_round_trip(&b);
+        match &rec.records[&1] {
+            Record::StringArray(vals) => assert_eq!(vals.len(), 4),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn bad_member_type() {
+        // C { a: String, b: 9 }, whose member types start at 32
+        let mut records = vec![5, 1, 0, 0, 0, 1, b'C', 2, 0, 0, 0, 1, b'a', 1, b'b', 1, 9];
+        records.extend(&[2, 0, 0, 0]);
+        match parse(&stream(&records)) {
+            Err(ParseError::BadMemberType {
+                offset: 33,
+                member_type: 9,
+            }) => (),
+            other => panic!("{:?}", other),
+        }
+
+        // An array with the member type 8
+        let array = stream(&[7, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 8]);
+        match parse(&array) {
+            Err(ParseError::BadMemberType {
+                offset: 31,
+                member_type: 8,
+            }) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn limits() {
+        // Tiny arrays that claim to have two billion elements
+        let nulls = stream(&[
+            16, 1, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0x7F, 14, 0xFF, 0xFF, 0xFF, 0x7F,
+        ]);
+        let primitive_nulls = stream(&[15, 1, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0x7F, 17]);
+        for bytes in &[nulls, primitive_nulls] {
+            match parse(bytes) {
+                Err(ParseError::AllocationLimit { offset: 22, .. }) => (),
+                other => panic!("{:?}", other),
+            }
+        }
+
+        // Object arrays, each inlined into the one before
+        let mut nested = Vec::new();
+        for id in 1..=100 {
+            nested.extend(&[16, id, 0, 0, 0, 1, 0, 0, 0]);
+        }
+        nested.push(10);
+        let nested = stream(&nested);
+        match parse(&nested) {
+            Err(ParseError::TooDeep { offset, limit: 64 }) => assert_eq!(offset, 17 + 65 * 9),
+            other => panic!("{:?}", other),
+        }
+        let mut options = ParserOptions {
+            max_depth: 100,
+            ..Default::default()
+        };
+        assert_eq!(options.parse(&nested).unwrap().records.len(), 100);
+        options.max_records = 99;
+        match options.parse(&nested) {
+            Err(ParseError::TooManyRecords {
+                offset: 17,
+                limit: 99,
+            }) => (),
+            other => panic!("{:?}", other),
+        }
+
+        let string = stream(&[6, 1, 0, 0, 0, 5, b'h', b'e', b'l', b'l', b'o']);
+        options.max_string_length = 4;
+        match options.parse(&string) {
+            Err(ParseError::StringTooLong {
+                offset: 22,
+                length: 5,
+                limit: 4,
+            }) => (),
+            other => panic!("{:?}", other),
+        }
     }
 }
@@ -0,0 +1,795 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use super::dump::ref_counts;
+use super::records::*;
+
+/// A path to members of a document, starting at the root record.
+///
+/// Segments are member names separated by `.` and indices in brackets, e.g.
+/// `inventory.upgrades._items[3].upgrade.name`. An index can be `*` to select all
+/// elements, or a comma-separated list of coordinates for multi-dimensional arrays.
+/// References are followed automatically. Indexing a `List<T>` indexes into its `_items`,
+/// limited to `_size`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Path {
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Member(String),
+    Index(Vec<i32>),
+    All,
+}
+
+/// Where a value selected by a path lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Location {
+    /// A whole record
+    Record(i32),
+    /// The member with the given index of a class, or the element of an array
+    Member(i32, usize),
+}
+
+#[derive(Debug)]
+pub enum Value<'a> {
+    Record(&'a Record),
+    Member(&'a Member),
+    Primitive(&'a Primitive),
+}
+
+#[derive(Debug)]
+pub enum ValueMut<'a> {
+    Record(&'a mut Record),
+    Member(&'a mut Member),
+    Primitive(&'a mut Primitive),
+}
+
+#[derive(Debug)]
+pub enum PathError {
+    Syntax {
+        position: usize,
+        message: &'static str,
+    },
+    NoSuchMember {
+        path: String,
+        member: String,
+    },
+    NotAClass {
+        path: String,
+    },
+    NotAnArray {
+        path: String,
+    },
+    IndexOutOfBounds {
+        path: String,
+        index: Vec<i32>,
+    },
+    Null {
+        path: String,
+    },
+    DanglingReference {
+        path: String,
+        id: i32,
+    },
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathError::Syntax { position, message } => {
+                write!(f, "Invalid path at position {}: {}", position, message)
+            }
+            PathError::NoSuchMember { path, member } => {
+                write!(f, "'{}' has no member '{}'", path, member)
+            }
+            PathError::NotAClass { path } => write!(f, "'{}' is not a class", path),
+            PathError::NotAnArray { path } => write!(f, "'{}' is not an array", path),
+            PathError::IndexOutOfBounds { path, index } => {
+                write!(f, "Index {:?} is out of bounds for '{}'", index, path)
+            }
+            PathError::Null { path } => write!(f, "'{}' is null", path),
+            PathError::DanglingReference { path, id } => {
+                write!(f, "'{}' references missing record {}", path, id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+/// Why `DeserializedRecord::set_value` couldn't set a value.
+#[derive(Debug)]
+pub enum SetValueError {
+    /// The value is null and not declared as a string, so there's no type to parse the new
+    /// value as
+    Null,
+    /// The value is a class, an array or a library
+    NotPrimitive,
+    InvalidValue(InvalidValue),
+}
+
+impl fmt::Display for SetValueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SetValueError::Null => write!(f, "Can't set a null value since its type is unknown"),
+            SetValueError::NotPrimitive => write!(f, "Only primitives and strings can be set"),
+            SetValueError::InvalidValue(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for SetValueError {}
+
+impl Path {
+    pub fn parse(s: &str) -> Result<Self, PathError> {
+        let mut segments = Vec::new();
+        let mut chars = s.char_indices().peekable();
+        let mut expect_name = !s.starts_with('[');
+
+        while let Some(&(position, c)) = chars.peek() {
+            if c == '[' {
+                chars.next();
+                let start = position + 1;
+                let mut end = start;
+                for (i, c) in &mut chars {
+                    end = i;
+                    if c == ']' {
+                        break;
+                    }
+                }
+                if end >= s.len() || s.as_bytes()[end] != b']' {
+                    return Err(PathError::Syntax {
+                        position,
+                        message: "unclosed '['",
+                    });
+                }
+                let index = &s[start..end];
+                if index.trim() == "*" {
+                    segments.push(Segment::All);
+                } else {
+                    let coords: Result<Vec<i32>, _> =
+                        index.split(',').map(|i| i.trim().parse()).collect();
+                    match coords {
+                        Ok(coords) => segments.push(Segment::Index(coords)),
+                        Err(_) => {
+                            return Err(PathError::Syntax {
+                                position: start,
+                                message: "expected '*' or a list of integers",
+                            })
+                        }
+                    }
+                }
+                expect_name = false;
+            } else if c == '.' && !expect_name {
+                chars.next();
+                expect_name = true;
+            } else if expect_name {
+                let start = position;
+                let mut end = s.len();
+                while let Some(&(i, c)) = chars.peek() {
+                    if c == '.' || c == '[' || c == ']' {
+                        end = i;
+                        break;
+                    }
+                    chars.next();
+                }
+                if start == end {
+                    return Err(PathError::Syntax {
+                        position,
+                        message: "expected a member name",
+                    });
+                }
+                segments.push(Segment::Member(s[start..end].into()));
+                expect_name = false;
+            } else {
+                return Err(PathError::Syntax {
+                    position,
+                    message: "expected '.' or '['",
+                });
+            }
+        }
+
+        if expect_name && !s.is_empty() {
+            return Err(PathError::Syntax {
+                position: s.len(),
+                message: "expected a member name",
+            });
+        }
+
+        Ok(Self { segments })
+    }
+
+    pub fn push(&mut self, segment: Segment) {
+        self.segments.push(segment);
+    }
+}
+
+impl FromStr for Path {
+    type Err = PathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Member(name) if i == 0 => write!(f, "{}", name)?,
+                Segment::Member(name) => write!(f, ".{}", name)?,
+                Segment::Index(coords) => {
+                    f.write_str("[")?;
+                    for (i, coord) in coords.iter().enumerate() {
+                        if i > 0 {
+                            f.write_str(",")?;
+                        }
+                        write!(f, "{}", coord)?;
+                    }
+                    f.write_str("]")?;
+                }
+                Segment::All => f.write_str("[*]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DeserializedRecord {
+    /// Find the locations of all values matching a path.
+    pub fn select(&self, path: &Path) -> Result<Vec<Location>, PathError> {
+        let mut current = vec![Location::Record(self.root_id)];
+        let mut prefix = Path::default();
+
+        for segment in &path.segments {
+            let mut next = Vec::new();
+            for &location in &current {
+                let id = self.follow(location, &prefix)?;
+                self.select_segment(id, segment, &prefix, &mut next)?;
+            }
+            current = next;
+            prefix.push(segment.clone());
+        }
+
+        Ok(current)
+    }
+
+    pub fn get(&self, location: Location) -> Option<Value<'_>> {
+        match location {
+            Location::Record(id) => self.records.get(&id).map(Value::Record),
+            Location::Member(id, index) => match self.records.get(&id)? {
+                Record::PrimitiveArray(_, vals) => vals.get(index).map(Value::Primitive),
+                record => match record_members(record)?.get(index)? {
+                    Member::Reference(id) => self.records.get(id).map(Value::Record),
+                    member => Some(Value::Member(member)),
+                },
+            },
+        }
+    }
+
+    /// Like `get`, but references are followed so editing a reference member edits the
+    /// referenced record. Use `member_mut` to change the reference itself.
+    pub fn get_mut(&mut self, location: Location) -> Option<ValueMut<'_>> {
+        let id = match location {
+            Location::Record(id) => id,
+            Location::Member(id, index) => match self.records.get(&id)? {
+                Record::PrimitiveArray(..) => id,
+                record => match record_members(record)?.get(index)? {
+                    Member::Reference(id) => *id,
+                    _ => id,
+                },
+            },
+        };
+        if let Location::Member(parent, index) = location {
+            if parent == id {
+                return match self.records.get_mut(&id)? {
+                    Record::PrimitiveArray(_, vals) => vals.get_mut(index).map(ValueMut::Primitive),
+                    record => record_members_mut(record)?
+                        .get_mut(index)
+                        .map(ValueMut::Member),
+                };
+            }
+        }
+        self.records.get_mut(&id).map(ValueMut::Record)
+    }
+
+    pub fn member_mut(&mut self, location: Location) -> Option<&mut Member> {
+        match location {
+            Location::Record(_) => None,
+            Location::Member(id, index) => {
+                record_members_mut(self.records.get_mut(&id)?)?.get_mut(index)
+            }
+        }
+    }
+
+    /// A one-line description of the value at a location, e.g. for printing what a path
+    /// selected. Primitives and strings are printed as they are, other records as a summary.
+    pub fn format_value(&self, location: Location) -> String {
+        match self.get(location) {
+            Some(Value::Record(Record::String(s))) => s.clone(),
+            Some(Value::Record(Record::Class(class))) => {
+                format!("<{}>", self.class_type(class).name)
+            }
+            Some(Value::Record(Record::BinaryLibrary(name))) => format!("<library {}>", name),
+            Some(Value::Record(Record::ObjectArray(vals)))
+            | Some(Value::Record(Record::StringArray(vals))) => {
+                format!("<array of {}>", vals.len())
+            }
+            Some(Value::Record(Record::BinaryArray(array))) => {
+                format!("<array of {}>", array.values.len())
+            }
+            Some(Value::Record(Record::PrimitiveArray(typ, vals))) => {
+                format!("<{:?} array of {}>", typ, vals.len())
+            }
+            Some(Value::Member(Member::Primitive(val))) | Some(Value::Primitive(val)) => {
+                val.to_string()
+            }
+            Some(Value::Member(Member::Null)) | Some(Value::Member(Member::NullMultiple(_))) => {
+                "null".into()
+            }
+            // `get` follows references, so it returns `None` for dangling ones
+            _ => "<missing>".into(),
+        }
+    }
+
+    /// Set a primitive or string to `value`, parsed as the type it already has. Strings
+    /// are edited in place, unless other members refer to the same string record. Then the
+    /// member gets a new string record instead, so the others keep their value. So does a
+    /// null member that is declared as a string.
+    pub fn set_value(&mut self, location: Location, value: &str) -> Result<(), SetValueError> {
+        self.set_values(&[location], value)
+    }
+
+    /// Like `set_value` for each of `locations`, but the references to the strings are only
+    /// counted once. Stops at the first error, leaving the earlier locations changed.
+    pub fn set_values(&mut self, locations: &[Location], value: &str) -> Result<(), SetValueError> {
+        let mut counts = ref_counts(self);
+        for &location in locations {
+            self.set_value_with_counts(location, value, &mut counts)?;
+        }
+        Ok(())
+    }
+
+    fn set_value_with_counts(
+        &mut self,
+        location: Location,
+        value: &str,
+        counts: &mut HashMap<i32, usize>,
+    ) -> Result<(), SetValueError> {
+        if let Location::Member(parent, index) = location {
+            let member = self
+                .records
+                .get(&parent)
+                .and_then(record_members)
+                .and_then(|m| m.get(index));
+            match member {
+                Some(&Member::Reference(id)) => {
+                    let count = counts.entry(id).or_insert(0);
+                    if matches!(self.records.get(&id), Some(Record::String(_))) && *count > 1 {
+                        *count -= 1;
+                        self.set_new_string(parent, index, value, counts);
+                        return Ok(());
+                    }
+                }
+                Some(Member::Null)
+                    if matches!(self.member_type(parent, index), Some(MemberType::String)) =>
+                {
+                    self.set_new_string(parent, index, value, counts);
+                    return Ok(());
+                }
+                _ => (),
+            }
+        }
+
+        match self.get_mut(location) {
+            Some(ValueMut::Member(Member::Primitive(val))) | Some(ValueMut::Primitive(val)) => {
+                *val = Primitive::parse(&val.primitive_type(), value)
+                    .map_err(SetValueError::InvalidValue)?;
+                Ok(())
+            }
+            Some(ValueMut::Record(Record::String(s))) => {
+                *s = value.into();
+                Ok(())
+            }
+            Some(ValueMut::Member(Member::Null)) => Err(SetValueError::Null),
+            _ => Err(SetValueError::NotPrimitive),
+        }
+    }
+
+    /// Point a member to a new string record.
+    fn set_new_string(
+        &mut self,
+        parent: i32,
+        index: usize,
+        value: &str,
+        counts: &mut HashMap<i32, usize>,
+    ) {
+        let new = self.add_record(Record::String(value.into()));
+        counts.insert(new, 1);
+        *self.member_mut(Location::Member(parent, index)).unwrap() = Member::Reference(new);
+        self.reinline(parent, &[new]);
+    }
+
+    /// The type a class or array declares for a member, if it declares one.
+    fn member_type(&self, parent: i32, index: usize) -> Option<&MemberType> {
+        match self.records.get(&parent)? {
+            Record::Class(class) => self.class_type(class).member_types.get(index),
+            Record::StringArray(_) => Some(&MemberType::String),
+            Record::BinaryArray(array) => Some(&array.member_type),
+            _ => None,
+        }
+    }
+
+    /// The id of the record at a location, following references.
+    fn follow(&self, location: Location, path: &Path) -> Result<i32, PathError> {
+        let (id, index) = match location {
+            Location::Record(id) => return Ok(id),
+            Location::Member(id, index) => (id, index),
+        };
+        let member = self
+            .records
+            .get(&id)
+            .and_then(record_members)
+            .and_then(|m| m.get(index));
+        match member {
+            Some(Member::Reference(id)) if self.records.contains_key(id) => Ok(*id),
+            Some(Member::Reference(id)) => Err(PathError::DanglingReference {
+                path: path.to_string(),
+                id: *id,
+            }),
+            Some(Member::Null) | Some(Member::NullMultiple(_)) => Err(PathError::Null {
+                path: path.to_string(),
+            }),
+            _ => Err(PathError::NotAClass {
+                path: path.to_string(),
+            }),
+        }
+    }
+
+    fn select_segment(
+        &self,
+        id: i32,
+        segment: &Segment,
+        path: &Path,
+        result: &mut Vec<Location>,
+    ) -> Result<(), PathError> {
+        let record = match self.records.get(&id) {
+            Some(record) => record,
+            None => {
+                return Err(PathError::DanglingReference {
+                    path: path.to_string(),
+                    id,
+                })
+            }
+        };
+        match segment {
+            Segment::Member(name) => {
+                let class = match record {
+                    Record::Class(class) => class,
+                    _ => {
+                        return Err(PathError::NotAClass {
+                            path: path.to_string(),
+                        })
+                    }
+                };
+                let index = self
+                    .class_type(class)
+                    .member_names
+                    .iter()
+                    .position(|n| n == name);
+                match index {
+                    Some(index) => result.push(Location::Member(id, index)),
+                    None => {
+                        return Err(PathError::NoSuchMember {
+                            path: path.to_string(),
+                            member: name.clone(),
+                        })
+                    }
+                }
+            }
+            Segment::Index(coords) => {
+                let (array_id, len) = self.array_len(id, path)?;
+                let index = match &self.records[&array_id] {
+                    Record::BinaryArray(array)
+                        if array.rank() != 1 || !array.lower_bounds.is_empty() =>
+                    {
+                        array.index_of(coords)
+                    }
+                    _ => match coords[..] {
+                        [i] if i >= 0 && (i as usize) < len => Some(i as usize),
+                        _ => None,
+                    },
+                };
+                match index {
+                    Some(index) => result.push(Location::Member(array_id, index)),
+                    None => {
+                        return Err(PathError::IndexOutOfBounds {
+                            path: path.to_string(),
+                            index: coords.clone(),
+                        })
+                    }
+                }
+            }
+            Segment::All => {
+                let (array_id, len) = self.array_len(id, path)?;
+                result.extend((0..len).map(|i| Location::Member(array_id, i)));
+            }
+        }
+        Ok(())
+    }
+
+    /// The id and length of the array behind a record. For a `List<T>` this is its `_items`.
+    fn array_len(&self, id: i32, path: &Path) -> Result<(i32, usize), PathError> {
+        let not_an_array = || PathError::NotAnArray {
+            path: path.to_string(),
+        };
+        match self.records.get(&id) {
+            Some(Record::Class(_)) => self
+                .list(id)
+                .map(|list| (list.items_id(), list.len()))
+                .ok_or_else(not_an_array),
+            Some(record) => array_record_len(record)
+                .map(|len| (id, len))
+                .ok_or_else(not_an_array),
+            None => Err(not_an_array()),
+        }
+    }
+}
+
+fn array_record_len(record: &Record) -> Option<usize> {
+    match record {
+        Record::ObjectArray(vals) | Record::StringArray(vals) => Some(vals.len()),
+        Record::PrimitiveArray(_, vals) => Some(vals.len()),
+        Record::BinaryArray(array) => Some(array.values.len()),
+        _ => None,
+    }
+}
+
+fn record_members(record: &Record) -> Option<&[Member]> {
+    match record {
+        Record::Class(class) => Some(&class.members),
+        Record::ObjectArray(vals) | Record::StringArray(vals) => Some(vals),
+        Record::BinaryArray(array) => Some(&array.values),
+        _ => None,
+    }
+}
+
+fn record_members_mut(record: &mut Record) -> Option<&mut Vec<Member>> {
+    match record {
+        Record::Class(class) => Some(&mut class.members),
+        Record::ObjectArray(vals) | Record::StringArray(vals) => Some(vals),
+        Record::BinaryArray(array) => Some(&mut array.values),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{class, class_type, document, int_list, save};
+    use crate::{parse, serialize};
+
+    fn select(rec: &DeserializedRecord, path: &str) -> Result<Vec<Location>, PathError> {
+        rec.select(&path.parse().unwrap())
+    }
+
+    fn syntax_error(path: &str) -> (usize, &'static str) {
+        match Path::parse(path) {
+            Err(PathError::Syntax { position, message }) => (position, message),
+            other => panic!("{}: {:?}", path, other),
+        }
+    }
+
+    #[test]
+    fn parse_paths() {
+        let path = Path::parse("inventory.upgrades[3][*][1, -2].name").unwrap();
+        assert_eq!(
+            path.segments,
+            [
+                Segment::Member("inventory".into()),
+                Segment::Member("upgrades".into()),
+                Segment::Index(vec![3]),
+                Segment::All,
+                Segment::Index(vec![1, -2]),
+                Segment::Member("name".into()),
+            ]
+        );
+        assert_eq!(path.to_string(), "inventory.upgrades[3][*][1,-2].name");
+        assert_eq!(Path::parse("[0].a").unwrap().to_string(), "[0].a");
+        assert!(Path::parse("").unwrap().segments.is_empty());
+
+        assert_eq!(syntax_error("a[1"), (1, "unclosed '['"));
+        assert_eq!(
+            syntax_error("a[x]"),
+            (2, "expected '*' or a list of integers")
+        );
+        assert_eq!(
+            syntax_error("a[1,]"),
+            (2, "expected '*' or a list of integers")
+        );
+        assert_eq!(syntax_error("a..b"), (2, "expected a member name"));
+        assert_eq!(syntax_error("a."), (2, "expected a member name"));
+        assert_eq!(syntax_error(".a"), (0, "expected a member name"));
+        assert_eq!(syntax_error("a]b"), (1, "expected '.' or '['"));
+        assert_eq!(
+            Path::parse("a[1").unwrap_err().to_string(),
+            "Invalid path at position 1: unclosed '['"
+        );
+    }
+
+    #[test]
+    fn select_all() {
+        let rec = save(1);
+        assert_eq!(select(&rec, "values[*]").unwrap(), [Location::Member(4, 0)]);
+        assert_eq!(select(&rec, "count").unwrap(), [Location::Member(2, 2)]);
+        assert_eq!(select(&rec, "").unwrap(), [Location::Record(2)]);
+
+        let err = select(&rec, "[*]").unwrap_err();
+        assert!(matches!(err, PathError::NotAnArray { .. }));
+        let err = select(&rec, "count.x").unwrap_err();
+        assert_eq!(err.to_string(), "'count' is not a class");
+        let err = select(&rec, "values[*].x").unwrap_err();
+        assert_eq!(err.to_string(), "'values[*]' is not a class");
+        let err = select(&rec, "missing").unwrap_err();
+        assert_eq!(err.to_string(), "'' has no member 'missing'");
+    }
+
+    #[test]
+    fn list_index_is_bounded_by_size() {
+        // `_items` has room for two elements, but only the first is in the list
+        let rec = int_list();
+        assert_eq!(select(&rec, "[*]").unwrap(), [Location::Member(2, 0)]);
+        assert_eq!(select(&rec, "[0]").unwrap(), [Location::Member(2, 0)]);
+        let err = select(&rec, "[1]").unwrap_err();
+        assert_eq!(err.to_string(), "Index [1] is out of bounds for ''");
+        assert!(select(&rec, "[-1]").is_err());
+        assert!(select(&rec, "[0,0]").is_err());
+
+        assert_eq!(select(&rec, "_items[*]").unwrap().len(), 2);
+        assert_eq!(select(&rec, "_items[1]").unwrap(), [Location::Member(2, 1)]);
+    }
+
+    #[test]
+    fn multi_dimensional_index() {
+        let ints = (0..6)
+            .map(|i| Member::Primitive(Primitive::Int32(i)))
+            .collect();
+        let rec = document(
+            1,
+            vec![
+                (1, class(0, vec![Member::Reference(2)])),
+                (
+                    2,
+                    Record::BinaryArray(BinaryArray {
+                        array_type: BinaryArrayType::RectangularOffset,
+                        lengths: vec![2, 3],
+                        lower_bounds: vec![1, 0],
+                        member_type: MemberType::Primitive(PrimitiveType::Int32),
+                        values: ints,
+                    }),
+                ),
+            ],
+            vec![class_type("Grid", &[("cells", MemberType::Object)])],
+        );
+
+        let cells = select(&rec, "cells[2,1]").unwrap();
+        assert_eq!(cells, [Location::Member(2, 4)]);
+        assert_eq!(rec.format_value(cells[0]), "4");
+        assert_eq!(select(&rec, "cells[*]").unwrap().len(), 6);
+        for path in &[
+            "cells[0,0]",
+            "cells[3,0]",
+            "cells[1,3]",
+            "cells[1]",
+            "cells[1,0,0]",
+        ] {
+            match select(&rec, path) {
+                Err(PathError::IndexOutOfBounds { .. }) => (),
+                other => panic!("{}: {:?}", path, other),
+            }
+        }
+    }
+
+    #[test]
+    fn set_value_type_mismatch() {
+        let mut rec = save(1);
+        let (values, count, ratio) = (
+            Location::Member(2, 1),
+            Location::Member(2, 2),
+            Location::Member(2, 3),
+        );
+        for value in &["abc", "1.5", "3000000000", ""] {
+            match rec.set_value(count, value) {
+                Err(SetValueError::InvalidValue(_)) => (),
+                other => panic!("{}: {:?}", value, other),
+            }
+        }
+        assert_eq!(rec.format_value(count), "5");
+        assert!(rec.set_value(ratio, "x").is_err());
+        rec.set_value(ratio, "0.25").unwrap();
+        assert_eq!(rec.format_value(ratio), "0.25");
+
+        assert!(matches!(
+            rec.set_value(values, "1"),
+            Err(SetValueError::NotPrimitive)
+        ));
+        *rec.member_mut(count).unwrap() = Member::Null;
+        assert!(matches!(
+            rec.set_value(count, "1"),
+            Err(SetValueError::Null)
+        ));
+    }
+
+    #[test]
+    fn set_null_string() {
+        let mut rec = save(1);
+        let title = Location::Member(2, 4);
+        *rec.member_mut(title).unwrap() = Member::Null;
+        rec.set_value(title, "title").unwrap();
+        assert_eq!(rec.records.len(), 5);
+        assert_eq!(string(&rec, title), "title");
+        assert_eq!(rec.format_value(Location::Member(2, 0)), "name");
+
+        let bytes = serialize(&rec);
+        assert_eq!(parse(&bytes).unwrap().format_value(title), "title");
+    }
+
+    fn string(rec: &DeserializedRecord, location: Location) -> &str {
+        match rec.get(location) {
+            Some(Value::Record(record)) => record.as_string(),
+            _ => panic!("Not a string"),
+        }
+    }
+
+    #[test]
+    fn set_unshared_string() {
+        let mut rec = save(1);
+        let values = Location::Member(2, 1);
+        rec.records.insert(5, Record::String("unshared".into()));
+        *rec.member_mut(values).unwrap() = Member::Reference(5);
+
+        rec.set_value(values, "edited").unwrap();
+        assert_eq!(rec.records.len(), 5);
+        assert_eq!(rec.records[&5].as_string(), "edited");
+    }
+
+    #[test]
+    fn set_values_counts_once() {
+        // Splitting `title` off leaves `name` as the only reference, so it's edited in place
+        let mut rec = save(1);
+        let (name, title) = (Location::Member(2, 0), Location::Member(2, 4));
+        rec.set_values(&[title, name], "new").unwrap();
+        assert_eq!(rec.records.len(), 5);
+        assert_eq!(string(&rec, name), "new");
+        assert_eq!(string(&rec, title), "new");
+        assert_eq!(rec.records[&3].as_string(), "new");
+
+        let mut shared = save(1);
+        shared.set_values(&[title], "title").unwrap();
+        assert_eq!(string(&shared, name), "name");
+        assert_eq!(shared.records.len(), 5);
+    }
+
+    #[test]
+    fn set_shared_string() {
+        // `name` and `title` share a string, which is inlined into `name`
+        let mut rec = parse(&serialize(&save(1))).unwrap();
+        let (name, title) = (Location::Member(2, 0), Location::Member(2, 4));
+
+        rec.set_value(title, "title").unwrap();
+        assert_eq!(rec.records.len(), 5);
+        assert_eq!(string(&rec, name), "name");
+        assert_eq!(rec.layout.inlined[&(2, 4)], [5]);
+        // Only referenced once now, so edited in place
+        rec.set_value(title, "new title").unwrap();
+        rec.set_value(name, "new name").unwrap();
+        assert_eq!(rec.records.len(), 5);
+
+        let rec = parse(&serialize(&rec)).unwrap();
+        assert_eq!(string(&rec, name), "new name");
+        assert_eq!(string(&rec, title), "new title");
+    }
+}
@@ -1,11 +1,20 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
 
+/// A whole deserialized stream.
 #[derive(Debug, Clone)]
 pub struct DeserializedRecord {
+    /// Id of the top-level object
     pub root_id: i32,
     pub header_id: i32,
+    /// All records by their object id. References between records are `Member::Reference`s.
     pub records: HashMap<i32, Record>,
+    /// Class metadata, referenced by `Class::class_type_id`
     pub class_types: Vec<ClassType>,
+    pub layout: Layout,
+    /// Where everything was found in the input, if the parser was asked to track it
+    pub spans: Option<Spans>,
 }
 
 impl DeserializedRecord {
@@ -13,16 +22,183 @@ impl DeserializedRecord {
         &self.class_types[class.class_type_id]
     }
 
-    pub fn class_member<'a, 'b>(&'a self, class: &'a Class, name: &'b str) -> &'a Member {
+    pub fn class_member<'a>(&'a self, class: &'a Class, name: &str) -> &'a Member {
         &class.members[self.class_member_index(class, name)]
     }
 
-    pub fn class_member_deref<'a, 'b>(&'a self, class: &'a Class, name: &'b str) -> &'a Record {
+    /// Look up the record a reference member points to.
+    pub fn class_member_deref<'a>(&'a self, class: &'a Class, name: &str) -> &'a Record {
         let id = self.class_member(class, name).as_reference();
         &self.records[id]
     }
 
-    pub fn class_member_index<'a, 'b>(&'a self, class: &'a Class, name: &'b str) -> usize {
+    /// Remove all records that can't be reached from the root record, and libraries that
+    /// no remaining class or member type refers to. Their layout entries are removed too.
+    pub fn gc(&mut self) {
+        let mut reachable = HashSet::new();
+        let mut libraries = HashSet::new();
+        let mut todo = vec![self.root_id];
+
+        while let Some(id) = todo.pop() {
+            if !reachable.insert(id) {
+                continue;
+            }
+            let members = match self.records.get(&id) {
+                Some(Record::Class(class)) => {
+                    let class_type = self.class_type(class);
+                    if !class_type.system_class {
+                        libraries.insert(class_type.library_id);
+                    }
+                    libraries.extend(
+                        class_type
+                            .member_types
+                            .iter()
+                            .filter_map(MemberType::library_id),
+                    );
+                    &class.members[..]
+                }
+                Some(Record::ObjectArray(vals)) | Some(Record::StringArray(vals)) => &vals[..],
+                Some(Record::BinaryArray(array)) => {
+                    libraries.extend(array.member_type.library_id());
+                    &array.values[..]
+                }
+                _ => continue,
+            };
+            for member in members {
+                if let Member::Reference(id) = member {
+                    todo.push(*id);
+                }
+            }
+        }
+
+        self.records.retain(|id, record| match record {
+            Record::BinaryLibrary(_) => libraries.contains(id),
+            _ => reachable.contains(id),
+        });
+
+        let records = &self.records;
+        let layout = &mut self.layout;
+        layout.records.retain(|id| records.contains_key(id));
+        layout
+            .inlined
+            .retain(|(parent, _), _| records.contains_key(parent));
+        layout.class_with_id.retain(|id| records.contains_key(id));
+        layout
+            .null_runs
+            .retain(|(parent, _), _| records.contains_key(parent));
+    }
+
+    /// Renumber all records to 1, 2, 3, ..., starting with the root and keeping the order
+    /// of the others. References, library ids and the layout are updated to match.
+    /// References to missing records get ids after all existing ones.
+    pub fn compact_ids(&mut self) {
+        let mut ids: Vec<i32> = self.records.keys().copied().collect();
+        ids.sort_unstable_by_key(|&id| (id != self.root_id, id));
+        let mut missing = Vec::new();
+        for record in self.records.values() {
+            for member in record_members(record) {
+                if let Member::Reference(id) = member {
+                    if !self.records.contains_key(id) {
+                        missing.push(*id);
+                    }
+                }
+            }
+        }
+        missing.sort_unstable();
+        missing.dedup();
+
+        let new_ids: HashMap<i32, i32> = ids.into_iter().chain(missing).zip(1..).collect();
+        let map = |id: &mut i32| {
+            if let Some(&new_id) = new_ids.get(id) {
+                *id = new_id;
+            }
+        };
+
+        let records = std::mem::take(&mut self.records);
+        for (mut id, mut record) in records {
+            map(&mut id);
+            match &mut record {
+                Record::Class(class) => class.members.iter_mut().for_each(|m| map_member(m, map)),
+                Record::ObjectArray(vals) | Record::StringArray(vals) => {
+                    vals.iter_mut().for_each(|m| map_member(m, map))
+                }
+                Record::BinaryArray(array) => {
+                    array.values.iter_mut().for_each(|m| map_member(m, map));
+                    map_member_type(&mut array.member_type, map);
+                }
+                _ => (),
+            }
+            self.records.insert(id, record);
+        }
+        map(&mut self.root_id);
+
+        for class_type in &mut self.class_types {
+            if !class_type.system_class {
+                map(&mut class_type.library_id);
+            }
+            for member_type in &mut class_type.member_types {
+                map_member_type(member_type, map);
+            }
+        }
+
+        let layout = std::mem::take(&mut self.layout);
+        let map_key = |(mut parent, index): (i32, usize)| {
+            map(&mut parent);
+            (parent, index)
+        };
+        self.layout = Layout {
+            records: layout
+                .records
+                .into_iter()
+                .map(|mut id| {
+                    map(&mut id);
+                    id
+                })
+                .collect(),
+            inlined: layout
+                .inlined
+                .into_iter()
+                .map(|(key, mut ids)| {
+                    ids.iter_mut().for_each(map);
+                    (map_key(key), ids)
+                })
+                .collect(),
+            class_with_id: layout
+                .class_with_id
+                .into_iter()
+                .map(|mut id| {
+                    map(&mut id);
+                    id
+                })
+                .collect(),
+            null_runs: layout
+                .null_runs
+                .into_iter()
+                .map(|(key, run)| (map_key(key), run))
+                .collect(),
+        };
+
+        if let Some(spans) = &mut self.spans {
+            let map_id = |(mut id, span): (i32, Range<usize>)| {
+                map(&mut id);
+                (id, span)
+            };
+            spans.records = std::mem::take(&mut spans.records)
+                .into_iter()
+                .map(map_id)
+                .collect();
+            spans.class_metadata = std::mem::take(&mut spans.class_metadata)
+                .into_iter()
+                .map(map_id)
+                .collect();
+            spans.members = std::mem::take(&mut spans.members)
+                .into_iter()
+                .map(|(key, span)| (map_key(key), span))
+                .collect();
+        }
+    }
+
+    pub fn class_member_index<'a>(&'a self, class: &'a Class, name: &str) -> usize {
         let class_type = self.class_type(class);
         class_type
             .member_names
@@ -30,16 +206,122 @@ impl DeserializedRecord {
             .position(|n| n == name)
             .unwrap()
     }
+
+    /// Insert a record under a new, unused id and return the id.
+    pub fn add_record(&mut self, record: Record) -> i32 {
+        let id = self.records.keys().max().map_or(1, |id| id + 1);
+        self.records.insert(id, record);
+        id
+    }
+
+    /// Keep the records that were inlined into the members of `parent` inlined after the
+    /// members were moved around, and inline the records in `new` as well.
+    pub(crate) fn reinline(&mut self, parent: i32, new: &[i32]) {
+        let members: &[Member] = match self.records.get(&parent) {
+            Some(Record::Class(class)) => &class.members,
+            Some(Record::ObjectArray(vals)) | Some(Record::StringArray(vals)) => vals,
+            Some(Record::BinaryArray(array)) => &array.values,
+            _ => return,
+        };
+
+        let mut inlined: HashMap<i32, Vec<i32>> = new.iter().map(|&id| (id, vec![id])).collect();
+        self.layout.inlined.retain(|&(p, _), ids| {
+            if p != parent {
+                return true;
+            }
+            if let Some(&id) = ids.last() {
+                inlined.entry(id).or_insert_with(|| ids.clone());
+            }
+            false
+        });
+
+        for (i, member) in members.iter().enumerate() {
+            if let Member::Reference(id) = member {
+                if let Some(ids) = inlined.get(id) {
+                    self.layout.inlined.insert((parent, i), ids.clone());
+                }
+            }
+        }
+    }
+}
+
+fn record_members(record: &Record) -> &[Member] {
+    match record {
+        Record::Class(class) => &class.members,
+        Record::ObjectArray(vals) | Record::StringArray(vals) => vals,
+        Record::BinaryArray(array) => &array.values,
+        _ => &[],
+    }
+}
+
+fn map_member(member: &mut Member, map: impl Fn(&mut i32)) {
+    if let Member::Reference(id) = member {
+        map(id);
+    }
+}
+
+fn map_member_type(member_type: &mut MemberType, map: impl Fn(&mut i32)) {
+    if let MemberType::Class(_, library_id) = member_type {
+        map(library_id);
+    }
+}
+
+/// How the records were laid out in the parsed stream.
+/// Used by the serializer to reproduce the original bytes.
+/// Records and members without an entry here are laid out using the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct Layout {
+    /// Ids of the top-level records in the order they appeared
+    pub records: Vec<i32>,
+    /// Records that were inlined into a member, keyed by the id of the parent record and
+    /// the index of the member. The last id is the member itself, any before it are libraries.
+    pub inlined: HashMap<(i32, usize), Vec<i32>>,
+    /// Records that were written as `ClassWithId`
+    pub class_with_id: HashSet<i32>,
+    /// Runs of nulls, keyed by the id of the parent record and the index of the first null
+    pub null_runs: HashMap<(i32, usize), NullRun>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum NullRun {
+    /// `ObjectNullMultiple256`
+    Short(u8),
+    /// `ObjectNullMultiple`
+    Wide(i32),
+}
+
+impl NullRun {
+    pub fn count(self) -> usize {
+        match self {
+            NullRun::Short(count) => count as usize,
+            NullRun::Wide(count) => count as usize,
+        }
+    }
+}
+
+/// Byte ranges in the parsed input. Every range includes everything nested in it,
+/// e.g. the span of a member includes the records that were inlined into it.
+#[derive(Debug, Clone, Default)]
+pub struct Spans {
+    /// Records by id, from the record type byte to the end of their last member
+    pub records: HashMap<i32, Range<usize>>,
+    /// Class metadata (name, member names and types and library id),
+    /// keyed by the id of the record that defined it
+    pub class_metadata: HashMap<i32, Range<usize>>,
+    /// Members and array elements, keyed like `Layout::null_runs`.
+    /// A run of nulls only has an entry for its first index.
+    pub members: HashMap<(i32, usize), Range<usize>>,
 }
 
+/// A record that has an object id.
 #[derive(Debug, Clone)]
 pub enum Record {
     BinaryLibrary(String),
     Class(Class),
-    // ObjectArray(Vec<Member>),
-    BinaryArray(MemberType, Vec<Member>),
+    ObjectArray(Vec<Member>),
+    BinaryArray(BinaryArray),
     PrimitiveArray(PrimitiveType, Vec<Primitive>),
-    // StringArray(Vec<String>),
+    StringArray(Vec<Member>),
     String(String),
 }
 
@@ -53,8 +335,8 @@ impl Record {
     }
 
     pub fn as_binary_array(&self) -> &[Member] {
-        if let Self::BinaryArray(_, array) = self {
-            array
+        if let Self::BinaryArray(array) = self {
+            &array.values
         } else {
             panic!("Record is not an BinaryArray")
         }
@@ -68,8 +350,8 @@ impl Record {
         }
     }
 
-    pub fn as_binary_array_mut(&mut self) -> &mut Vec<Member> {
-        if let Self::BinaryArray(_, array) = self {
+    pub fn as_binary_array_mut(&mut self) -> &mut BinaryArray {
+        if let Self::BinaryArray(array) = self {
             array
         } else {
             panic!("Record is not an BinaryArray")
@@ -85,17 +367,93 @@ impl Record {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryArrayType {
+    Single,
+    Jagged,
+    Rectangular,
+    SingleOffset,
+    JaggedOffset,
+    RectangularOffset,
+}
+
+impl BinaryArrayType {
+    pub const fn has_lower_bounds(self) -> bool {
+        matches!(
+            self,
+            Self::SingleOffset | Self::JaggedOffset | Self::RectangularOffset
+        )
+    }
+}
+
+/// An array with arbitrary rank and lower bounds.
+/// The values of multi-dimensional arrays are stored in row-major order.
+#[derive(Debug, Clone)]
+pub struct BinaryArray {
+    pub array_type: BinaryArrayType,
+    pub lengths: Vec<i32>,
+    /// Empty unless `array_type` is one of the offset types
+    pub lower_bounds: Vec<i32>,
+    pub member_type: MemberType,
+    pub values: Vec<Member>,
+}
+
+impl BinaryArray {
+    pub fn rank(&self) -> usize {
+        self.lengths.len()
+    }
+
+    /// Convert coordinates (taking lower bounds into account) into an index into `values`.
+    pub fn index_of(&self, coords: &[i32]) -> Option<usize> {
+        if coords.len() != self.rank() {
+            return None;
+        }
+        let mut index = 0;
+        for (dim, &coord) in coords.iter().enumerate() {
+            let lower_bound = self.lower_bounds.get(dim).copied().unwrap_or(0);
+            let coord = coord.checked_sub(lower_bound)?;
+            if coord < 0 || coord >= self.lengths[dim] {
+                return None;
+            }
+            index = index * self.lengths[dim] as usize + coord as usize;
+        }
+        Some(index)
+    }
+
+    pub fn get(&self, coords: &[i32]) -> Option<&Member> {
+        self.values.get(self.index_of(coords)?)
+    }
+
+    pub fn get_mut(&mut self, coords: &[i32]) -> Option<&mut Member> {
+        let index = self.index_of(coords)?;
+        self.values.get_mut(index)
+    }
+
+    /// Append a value to a one-dimensional array.
+    pub fn push(&mut self, val: Member) -> Result<(), NotOneDimensional> {
+        if self.rank() != 1 {
+            return Err(NotOneDimensional { rank: self.rank() });
+        }
+        self.values.push(val);
+        self.lengths[0] += 1;
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Class {
     pub class_type_id: usize,
     pub members: Vec<Member>,
 }
 
+/// The metadata of a class: its name, library and members.
 #[derive(Debug, Clone)]
 pub struct ClassType {
     pub name: String,
     pub library_id: i32,
     pub system_class: bool,
+    /// Whether the member types were serialized. If not, all `member_types` are `Object`.
+    pub has_member_types: bool,
     pub member_names: Vec<String>,
     pub member_types: Vec<MemberType>,
 }
@@ -112,6 +470,17 @@ pub enum MemberType {
     PrimitiveArray(PrimitiveType),
 }
 
+impl MemberType {
+    /// The library of a `Class` member type.
+    pub const fn library_id(&self) -> Option<i32> {
+        match self {
+            MemberType::Class(_, library_id) => Some(*library_id),
+            _ => None,
+        }
+    }
+}
+
+/// A member value of a class or an element of an array.
 #[derive(Debug, Clone)]
 pub enum Member {
     Primitive(Primitive),
@@ -136,9 +505,17 @@ impl Member {
             panic!("Member is not a Reference")
         }
     }
+
+    pub const fn as_bool(&self) -> bool {
+        if let Self::Primitive(Primitive::Boolean(val)) = self {
+            *val
+        } else {
+            panic!("Member is not a Boolean")
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PrimitiveType {
     Boolean,
     Byte,
@@ -159,7 +536,7 @@ pub enum PrimitiveType {
     String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Primitive {
     Boolean(bool),
     Byte(u8),
@@ -202,4 +579,307 @@ impl Primitive {
             Primitive::String(..) => PrimitiveType::String,
         }
     }
+
+    /// Parse a value of the given type from its textual representation.
+    pub fn parse(typ: &PrimitiveType, s: &str) -> Result<Primitive, InvalidValue> {
+        let invalid = || InvalidValue {
+            primitive_type: typ.clone(),
+            value: s.into(),
+        };
+        Ok(match typ {
+            PrimitiveType::Boolean => Primitive::Boolean(s.parse().map_err(|_| invalid())?),
+            PrimitiveType::Byte => Primitive::Byte(s.parse().map_err(|_| invalid())?),
+            PrimitiveType::Char => {
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if (c as u32) <= 0xFFFF => Primitive::Char(c),
+                    _ => return Err(invalid()),
+                }
+            }
+            PrimitiveType::Decimal if is_decimal(s) => Primitive::Decimal(s.into()),
+            PrimitiveType::Decimal => return Err(invalid()),
+            PrimitiveType::Double => Primitive::Double(s.parse().map_err(|_| invalid())?),
+            PrimitiveType::Int16 => Primitive::Int16(s.parse().map_err(|_| invalid())?),
+            PrimitiveType::Int32 => Primitive::Int32(s.parse().map_err(|_| invalid())?),
+            PrimitiveType::Int64 => Primitive::Int64(s.parse().map_err(|_| invalid())?),
+            PrimitiveType::Int8 => Primitive::Int8(s.parse().map_err(|_| invalid())?),
+            PrimitiveType::Single => Primitive::Single(s.parse().map_err(|_| invalid())?),
+            PrimitiveType::TimeSpan => Primitive::TimeSpan(s.parse().map_err(|_| invalid())?),
+            PrimitiveType::DateTime => Primitive::DateTime(s.parse().map_err(|_| invalid())?),
+            PrimitiveType::UInt16 => Primitive::UInt16(s.parse().map_err(|_| invalid())?),
+            PrimitiveType::UInt32 => Primitive::UInt32(s.parse().map_err(|_| invalid())?),
+            PrimitiveType::UInt64 => Primitive::UInt64(s.parse().map_err(|_| invalid())?),
+            PrimitiveType::Null if s == "null" => Primitive::Null,
+            PrimitiveType::Null => return Err(invalid()),
+            PrimitiveType::String => Primitive::String(s.into()),
+        })
+    }
+}
+
+/// The largest value of a .NET `Decimal`
+const MAX_DECIMAL: &str = "79228162514264337593543950335";
+
+/// Whether `s` is a number that .NET's `Decimal.Parse` reads back: an optional sign, digits
+/// and optionally a fraction, without an exponent and no larger than a `Decimal` can hold.
+fn is_decimal(s: &str) -> bool {
+    let unsigned = s.strip_prefix(|c| c == '-' || c == '+').unwrap_or(s);
+    let (integer, fraction) = match unsigned.find('.') {
+        Some(point) => (&unsigned[..point], &unsigned[point + 1..]),
+        None => (unsigned, "0"),
+    };
+    let digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    if !digits(integer) || !digits(fraction) {
+        return false;
+    }
+    let integer = integer.trim_start_matches('0');
+    integer.len() < MAX_DECIMAL.len()
+        || (integer.len() == MAX_DECIMAL.len() && integer <= MAX_DECIMAL)
+}
+
+impl fmt::Display for Primitive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Primitive::Boolean(val) => write!(f, "{}", val),
+            Primitive::Byte(val) => write!(f, "{}", val),
+            Primitive::Char(val) => write!(f, "{}", val),
+            Primitive::Decimal(val) => write!(f, "{}", val),
+            Primitive::Double(val) => write!(f, "{}", val),
+            Primitive::Int16(val) => write!(f, "{}", val),
+            Primitive::Int32(val) => write!(f, "{}", val),
+            Primitive::Int64(val) => write!(f, "{}", val),
+            Primitive::Int8(val) => write!(f, "{}", val),
+            Primitive::Single(val) => write!(f, "{}", val),
+            Primitive::TimeSpan(val) => write!(f, "{}", val),
+            Primitive::DateTime(val) => write!(f, "{}", val),
+            Primitive::UInt16(val) => write!(f, "{}", val),
+            Primitive::UInt32(val) => write!(f, "{}", val),
+            Primitive::UInt64(val) => write!(f, "{}", val),
+            Primitive::Null => write!(f, "null"),
+            Primitive::String(val) => write!(f, "{}", val),
+        }
+    }
+}
+
+/// A value that couldn't be parsed as the requested primitive type.
+#[derive(Debug)]
+pub struct InvalidValue {
+    pub primitive_type: PrimitiveType,
+    pub value: String,
+}
+
+impl fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "'{}' is not a valid {:?}",
+            self.value, self.primitive_type
+        )
+    }
+}
+
+impl std::error::Error for InvalidValue {}
+
+/// Returned when pushing to a `BinaryArray` with more or less than one dimension.
+#[derive(Debug)]
+pub struct NotOneDimensional {
+    pub rank: usize,
+}
+
+impl fmt::Display for NotOneDimensional {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Can only push to one-dimensional arrays, not to arrays of rank {}",
+            self.rank
+        )
+    }
+}
+
+impl std::error::Error for NotOneDimensional {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{push_i32, sample_stream, stream};
+    use crate::parser::parse;
+    use crate::serializer::serialize;
+
+    #[test]
+    fn gc_and_compact_ids() {
+        let bytes = sample_stream();
+        let mut rec = parse(&bytes).unwrap();
+        rec.records
+            .insert(20, Record::BinaryLibrary("Unused".into()));
+        rec.records.insert(21, Record::String("garbage".into()));
+        rec.gc();
+        assert_eq!(serialize(&rec), bytes);
+
+        // Dropping both instances of `Other` makes its library unused
+        rec.records.get_mut(&1).unwrap().as_class_mut().members[2] = Member::Null;
+        match rec.records.get_mut(&5).unwrap() {
+            Record::ObjectArray(vals) => vals[0] = Member::Null,
+            _ => unreachable!(),
+        }
+        rec.gc();
+        let mut ids: Vec<i32> = rec.records.keys().copied().collect();
+        ids.sort_unstable();
+        assert_eq!(ids, [1, 2, 3, 4, 5, 9, 10]);
+
+        let mut compacted = rec.clone();
+        compacted.compact_ids();
+        let mut ids: Vec<i32> = compacted.records.keys().copied().collect();
+        ids.sort_unstable();
+        assert_eq!(ids, (1..=7).collect::<Vec<_>>());
+
+        let reparsed = parse(&serialize(&compacted)).unwrap();
+        assert!(reparsed.structurally_eq(&rec));
+    }
+
+    /// A `BinaryArray` record with id 1 and the given type, lengths and lower bounds.
+    fn binary_array(array_type: u8, lengths: &[i32], lower_bounds: &[i32]) -> Vec<u8> {
+        let mut b = vec![7];
+        push_i32(&mut b, 1);
+        b.push(array_type);
+        push_i32(&mut b, lengths.len() as i32);
+        for &i in lengths.iter().chain(lower_bounds) {
+            push_i32(&mut b, i);
+        }
+        b
+    }
+
+    /// Parse a stream, check that it's written back unchanged and return its root array.
+    fn round_trip(records: &[u8]) -> BinaryArray {
+        let bytes = stream(records);
+        let rec = parse(&bytes).unwrap();
+        assert_eq!(serialize(&rec), bytes);
+        match &rec.records[&1] {
+            Record::BinaryArray(array) => array.clone(),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn int_at(array: &BinaryArray, coords: &[i32]) -> Option<i32> {
+        array.get(coords).map(Member::as_i32)
+    }
+
+    /// An array of `Int32`s with the values 0, 1, 2, ...
+    fn ints(mut b: Vec<u8>, count: i32) -> Vec<u8> {
+        b.extend(&[0, 8]);
+        for i in 0..count {
+            push_i32(&mut b, i);
+        }
+        b
+    }
+
+    #[test]
+    fn rectangular_array() {
+        let array = round_trip(&ints(binary_array(2, &[2, 3], &[]), 6));
+        assert_eq!(array.array_type, BinaryArrayType::Rectangular);
+        assert_eq!(int_at(&array, &[0, 0]), Some(0));
+        assert_eq!(int_at(&array, &[0, 2]), Some(2));
+        assert_eq!(int_at(&array, &[1, 0]), Some(3));
+        assert_eq!(int_at(&array, &[1, 2]), Some(5));
+        for coords in &[&[2, 0][..], &[0, 3], &[-1, 0], &[0, -1], &[1], &[0, 0, 0]] {
+            assert!(array.get(coords).is_none(), "{:?}", coords);
+        }
+    }
+
+    #[test]
+    fn offset_arrays() {
+        let array = round_trip(&ints(binary_array(3, &[3], &[10]), 3));
+        assert_eq!(array.lower_bounds, [10]);
+        assert_eq!(int_at(&array, &[10]), Some(0));
+        assert_eq!(int_at(&array, &[12]), Some(2));
+        for coords in &[[0], [9], [13], [i32::MIN], [i32::MAX]] {
+            assert!(array.get(coords).is_none(), "{:?}", coords);
+        }
+
+        let mut array = round_trip(&ints(binary_array(5, &[2, 2], &[1, -1]), 4));
+        assert_eq!(array.array_type, BinaryArrayType::RectangularOffset);
+        assert_eq!(int_at(&array, &[1, -1]), Some(0));
+        assert_eq!(int_at(&array, &[1, 0]), Some(1));
+        assert_eq!(int_at(&array, &[2, -1]), Some(2));
+        *array.get_mut(&[2, 0]).unwrap() = Member::Primitive(Primitive::Int32(7));
+        assert_eq!(array.values[3].as_i32(), 7);
+        for coords in &[[0, 0], [3, 0], [1, -2], [1, 1], [i32::MIN, 0]] {
+            assert!(array.get(coords).is_none(), "{:?}", coords);
+            assert!(array.get_mut(coords).is_none(), "{:?}", coords);
+        }
+    }
+
+    #[test]
+    fn jagged_array() {
+        // int[][] { new int[] { 0, 1 }, null } with a lower bound of 1
+        let mut b = binary_array(4, &[2], &[1]);
+        b.extend(&[7, 8, 15]);
+        push_i32(&mut b, 2);
+        push_i32(&mut b, 2);
+        b.push(8);
+        push_i32(&mut b, 0);
+        push_i32(&mut b, 1);
+        b.push(10);
+        let array = round_trip(&b);
+        assert_eq!(array.array_type, BinaryArrayType::JaggedOffset);
+        assert!(matches!(array.get(&[1]), Some(Member::Reference(2))));
+        assert!(matches!(array.get(&[2]), Some(Member::Null)));
+        assert!(array.get(&[0]).is_none());
+        assert!(array.get(&[3]).is_none());
+        assert!(array.get(&[1, 0]).is_none());
+    }
+
+    #[test]
+    fn parse_decimal() {
+        for valid in &[
+            "0",
+            "-1",
+            "+2",
+            "3.25",
+            "007.50",
+            "-79228162514264337593543950335",
+        ] {
+            let parsed = Primitive::parse(&PrimitiveType::Decimal, valid).unwrap();
+            assert_eq!(parsed.to_string(), *valid);
+        }
+        for invalid in &[
+            "",
+            "-",
+            "1.",
+            ".5",
+            "1e5",
+            "1E5",
+            "inf",
+            "NaN",
+            "0x10",
+            " 1",
+            "1,000",
+            "1.2.3",
+            "79228162514264337593543950336",
+        ] {
+            assert!(
+                Primitive::parse(&PrimitiveType::Decimal, invalid).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn push_needs_one_dimension() {
+        let mut array = BinaryArray {
+            array_type: BinaryArrayType::Single,
+            lengths: vec![1],
+            lower_bounds: Vec::new(),
+            member_type: MemberType::Object,
+            values: vec![Member::Null],
+        };
+        array.push(Member::Null).unwrap();
+        assert_eq!(array.lengths, [2]);
+
+        for lengths in &[vec![], vec![1, 2]] {
+            array.lengths = lengths.clone();
+            let err = array.push(Member::Null).unwrap_err();
+            assert_eq!(err.rank, lengths.len());
+            assert_eq!(array.values.len(), 2);
+        }
+    }
 }
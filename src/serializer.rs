@@ -1,146 +1,266 @@
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Write};
 
 use byteorder::{LittleEndian, WriteBytesExt};
 
 use super::records::*;
 
+/// Serialize a document. Records, inlining, class metadata reuse and null runs
+/// follow `rec.layout`, so unmodified documents are reproduced byte for byte.
+///
+/// Panics if the document can't be represented in the format, e.g. because it contains
+/// a char outside of the Basic Multilingual Plane. Use [`serialize_into`] to get an error
+/// instead.
 pub fn serialize(rec: &DeserializedRecord) -> Vec<u8> {
-    Serializer::new().serialize(rec)
+    let mut output = Vec::with_capacity(0x1000);
+    if let Err(err) = serialize_into(rec, &mut output) {
+        panic!("Can't serialize the document: {}", err);
+    }
+    output
+}
+
+/// Serialize a document into `writer` as it is produced, like [`serialize`].
+/// Values that can't be represented in the format are reported as `InvalidInput` errors.
+///
+/// The stream is written in many small pieces, so `writer` should be buffered.
+pub fn serialize_into<W: Write>(rec: &DeserializedRecord, writer: W) -> io::Result<()> {
+    Serializer::new(rec, writer).serialize()
 }
 
-struct Serializer {
-    output: Vec<u8>,
+/// The length prefix of strings is encoded in at most five bytes of seven bits each, but
+/// .NET reads it into an `Int32`.
+pub(crate) const MAX_STRING_LENGTH: usize = 0x7FFF_FFFF;
+
+struct Serializer<'a, W> {
+    rec: &'a DeserializedRecord,
+    output: W,
     todo: VecDeque<i32>,
-    done: HashSet<i32>,
+    written: HashSet<i32>,
     class_metadata: HashMap<usize, i32>,
 }
 
-impl Serializer {
-    fn new() -> Self {
+impl<'a, W: Write> Serializer<'a, W> {
+    fn new(rec: &'a DeserializedRecord, output: W) -> Self {
         Self {
-            output: Vec::with_capacity(0x1000),
+            rec,
+            output,
             todo: VecDeque::new(),
-            done: HashSet::new(),
+            written: HashSet::new(),
             class_metadata: HashMap::new(),
         }
     }
 
-    fn add_todo(&mut self, id: i32) {
-        if !self.done.contains(&id) {
-            self.done.insert(id);
-            self.todo.push_back(id);
-        }
-    }
+    fn serialize(mut self) -> io::Result<()> {
+        let rec = self.rec;
 
-    fn serialize(mut self, rec: &DeserializedRecord) -> Vec<u8> {
-        self.write_u8(0);
-        self.write_i32(rec.root_id);
-        self.write_i32(rec.header_id);
-        self.write_i32(1);
-        self.write_i32(0);
+        self.write_u8(0)?;
+        self.write_i32(rec.root_id)?;
+        self.write_i32(rec.header_id)?;
+        self.write_i32(1)?;
+        self.write_i32(0)?;
 
-        for (id, record) in &rec.records {
-            if let Record::BinaryLibrary(_) = record {
-                self.add_todo(*id);
+        if rec.layout.records.is_empty() {
+            let mut libraries: Vec<_> = rec
+                .records
+                .iter()
+                .filter(|(_, record)| matches!(record, Record::BinaryLibrary(_)))
+                .map(|(id, _)| *id)
+                .collect();
+            libraries.sort_unstable();
+            self.todo.extend(libraries);
+        } else {
+            for &id in &rec.layout.records {
+                if rec.records.contains_key(&id) {
+                    self.write_record_once(id)?;
+                }
             }
         }
 
-        self.add_todo(rec.root_id);
+        // Everything that wasn't part of the original layout
+        self.todo.push_back(rec.root_id);
 
         while let Some(id) = self.todo.pop_front() {
-            self.write_record(rec, id, &rec.records[&id]);
+            self.write_record_once(id)?;
         }
 
-        self.write_u8(11);
+        self.write_u8(11)?;
+        self.output.flush()
+    }
 
-        self.output
+    /// Write a record unless it was already written. Missing records, e.g. a missing root
+    /// or the target of a dangling reference, are skipped.
+    fn write_record_once(&mut self, id: i32) -> io::Result<()> {
+        if let Some(record) = self.rec.records.get(&id) {
+            if self.written.insert(id) {
+                self.write_record(id, record)?;
+            }
+        }
+        Ok(())
     }
 
-    fn write_record(&mut self, recs: &DeserializedRecord, id: i32, record: &Record) {
+    fn write_record(&mut self, id: i32, record: &'a Record) -> io::Result<()> {
         match record {
             Record::BinaryLibrary(name) => {
-                self.write_u8(12);
-                self.write_i32(id);
-                self.write_string(name);
+                self.write_u8(12)?;
+                self.write_i32(id)?;
+                self.write_string(name)?;
             }
             Record::Class(class) => {
-                let class_type = recs.class_type(class);
-                if class_type.system_class {
-                    self.write_u8(4);
-                    self.write_class_type(id, class_type);
-                } else if let Some(class_id) = self.class_metadata.get(&class.class_type_id) {
-                    let class_id = *class_id;
-                    self.write_u8(1);
-                    self.write_i32(id);
-                    self.write_i32(class_id);
-                } else {
-                    self.write_u8(5);
-                    self.write_class_type(id, class_type);
-                    self.write_i32(class_type.library_id);
-                    self.class_metadata.insert(class.class_type_id, id);
-                }
-                for (member, member_type) in
-                    class.members.iter().zip(class_type.member_types.iter())
-                {
-                    self.write_member(member, member_type);
+                let class_type = self.rec.class_type(class);
+                let metadata_id = self.class_metadata.get(&class.class_type_id).copied();
+                match metadata_id {
+                    Some(metadata_id)
+                        if !class_type.system_class
+                            || self.rec.layout.class_with_id.contains(&id) =>
+                    {
+                        self.write_u8(1)?;
+                        self.write_i32(id)?;
+                        self.write_i32(metadata_id)?;
+                    }
+                    _ if class_type.system_class => {
+                        if class_type.has_member_types {
+                            self.write_u8(4)?;
+                            self.write_class_type(id, class_type)?;
+                        } else {
+                            self.write_u8(2)?;
+                            self.write_class_info(id, class_type)?;
+                        }
+                        self.class_metadata.entry(class.class_type_id).or_insert(id);
+                    }
+                    _ => {
+                        // The library has to be known before any class that uses it
+                        let library = self.rec.records.get(&class_type.library_id);
+                        if let Some(Record::BinaryLibrary(_)) = library {
+                            self.write_record_once(class_type.library_id)?;
+                        }
+                        if class_type.has_member_types {
+                            self.write_u8(5)?;
+                            self.write_class_type(id, class_type)?;
+                        } else {
+                            self.write_u8(3)?;
+                            self.write_class_info(id, class_type)?;
+                        }
+                        self.write_i32(class_type.library_id)?;
+                        self.class_metadata.insert(class.class_type_id, id);
+                    }
                 }
+                self.write_members(id, &class.members, |i| class_type.member_types.get(i))?;
             }
-            // Record::ObjectArray(vals) => {
-            //     self.write_u8(16);
-            //     self.write_i32(id);
-            //     self.write_i32(vals.len() as i32);
-            //     for val in vals {
-            //         self.write_member(val, &MemberType::Object);
-            //     }
-            // }
-            Record::BinaryArray(typ, vals) => {
-                self.write_u8(7);
-                self.write_i32(id);
-                self.write_u8(0);
-                self.write_i32(1);
-                self.write_i32(vals.len() as i32);
-                self.write_member_type(typ);
-                self.write_member_type_additional_info(typ);
-                for val in vals {
-                    self.write_member(val, typ);
+            Record::ObjectArray(vals) => {
+                self.write_u8(16)?;
+                self.write_i32(id)?;
+                self.write_i32(vals.len() as i32)?;
+                self.write_members(id, vals, |_| Some(&MemberType::Object))?;
+            }
+            Record::StringArray(vals) => {
+                self.write_u8(17)?;
+                self.write_i32(id)?;
+                self.write_i32(vals.len() as i32)?;
+                self.write_members(id, vals, |_| Some(&MemberType::String))?;
+            }
+            Record::BinaryArray(array) => {
+                self.write_u8(7)?;
+                self.write_i32(id)?;
+                self.write_u8(match array.array_type {
+                    BinaryArrayType::Single => 0,
+                    BinaryArrayType::Jagged => 1,
+                    BinaryArrayType::Rectangular => 2,
+                    BinaryArrayType::SingleOffset => 3,
+                    BinaryArrayType::JaggedOffset => 4,
+                    BinaryArrayType::RectangularOffset => 5,
+                })?;
+                self.write_i32(array.rank() as i32)?;
+                for &length in &array.lengths {
+                    self.write_i32(length)?;
+                }
+                if array.array_type.has_lower_bounds() {
+                    for &lower_bound in &array.lower_bounds {
+                        self.write_i32(lower_bound)?;
+                    }
                 }
+                self.write_member_type(&array.member_type)?;
+                self.write_member_type_additional_info(&array.member_type)?;
+                self.write_members(id, &array.values, |_| Some(&array.member_type))?;
             }
             Record::PrimitiveArray(typ, vals) => {
-                self.write_u8(15);
-                self.write_i32(id);
-                self.write_i32(vals.len() as i32);
-                self.write_primitive_type(typ);
+                self.write_u8(15)?;
+                self.write_i32(id)?;
+                self.write_i32(vals.len() as i32)?;
+                self.write_primitive_type(typ)?;
                 for val in vals {
-                    self.write_primitive(val);
+                    self.write_primitive(val)?;
                 }
             }
             Record::String(val) => {
-                self.write_u8(6);
-                self.write_i32(id);
-                self.write_string(val);
+                self.write_u8(6)?;
+                self.write_i32(id)?;
+                self.write_string(val)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the members of a record. Stops at the first member without a type.
+    fn write_members(
+        &mut self,
+        parent: i32,
+        members: &[Member],
+        member_type: impl Fn(usize) -> Option<&'a MemberType>,
+    ) -> io::Result<()> {
+        let mut i = 0;
+        while let (Some(member), Some(typ)) = (members.get(i), member_type(i)) {
+            if let Some(&run) = self.rec.layout.null_runs.get(&(parent, i)) {
+                let end = i + run.count();
+                let is_run = end > i
+                    && end <= members.len()
+                    && members[i..end].iter().all(|m| matches!(m, Member::Null))
+                    && !matches!(typ, MemberType::Primitive(_));
+                if is_run {
+                    match run {
+                        NullRun::Short(count) => {
+                            self.write_u8(13)?;
+                            self.write_u8(count)?;
+                        }
+                        NullRun::Wide(count) => {
+                            self.write_u8(14)?;
+                            self.write_i32(count)?;
+                        }
+                    }
+                    i = end;
+                    continue;
+                }
             }
+            self.write_member(parent, i, member, typ)?;
+            i += 1;
         }
+        Ok(())
     }
 
-    fn write_class_type(&mut self, id: i32, class_type: &ClassType) {
-        self.write_i32(id);
-        self.write_string(&class_type.name);
-        self.write_i32(class_type.member_names.len() as i32);
+    fn write_class_info(&mut self, id: i32, class_type: &ClassType) -> io::Result<()> {
+        self.write_i32(id)?;
+        self.write_string(&class_type.name)?;
+        self.write_i32(class_type.member_names.len() as i32)?;
 
         for name in &class_type.member_names {
-            self.write_string(name);
+            self.write_string(name)?;
         }
+        Ok(())
+    }
+
+    fn write_class_type(&mut self, id: i32, class_type: &ClassType) -> io::Result<()> {
+        self.write_class_info(id, class_type)?;
 
         for t in &class_type.member_types {
-            self.write_member_type(t);
+            self.write_member_type(t)?;
         }
 
         for t in &class_type.member_types {
-            self.write_member_type_additional_info(t);
+            self.write_member_type_additional_info(t)?;
         }
+        Ok(())
     }
 
-    fn write_member_type(&mut self, typ: &MemberType) {
+    fn write_member_type(&mut self, typ: &MemberType) -> io::Result<()> {
         match typ {
             MemberType::Primitive(_) => self.write_u8(0),
             MemberType::String => self.write_u8(1),
@@ -153,53 +273,81 @@ impl Serializer {
         }
     }
 
-    fn write_member_type_additional_info(&mut self, typ: &MemberType) {
+    fn write_member_type_additional_info(&mut self, typ: &MemberType) -> io::Result<()> {
         match typ {
             MemberType::Primitive(t) => self.write_primitive_type(t),
             MemberType::SystemClass(name) => self.write_string(name),
             MemberType::Class(s, i) => {
-                self.write_string(s);
-                self.write_i32(*i);
+                self.write_string(s)?;
+                self.write_i32(*i)
             }
             MemberType::PrimitiveArray(t) => self.write_primitive_type(t),
-            _ => (),
+            _ => Ok(()),
         }
     }
 
-    fn write_member(&mut self, member: &Member, t: &MemberType) {
-        if let MemberType::Primitive(_) = t {
-            if let Member::Primitive(val) = member {
-                self.write_primitive(val);
-            } else {
-                panic!("Non primitive member for primitive field");
+    fn write_member(
+        &mut self,
+        parent: i32,
+        index: usize,
+        member: &Member,
+        t: &MemberType,
+    ) -> io::Result<()> {
+        if let MemberType::Primitive(typ) = t {
+            match member {
+                Member::Primitive(val) if val.primitive_type() == *typ => self.write_primitive(val),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Member {} of record {} is {:?}, which doesn't fit {:?}",
+                        index, parent, member, typ
+                    ),
+                )),
             }
         } else {
             match member {
                 Member::Primitive(val) => {
-                    self.write_u8(8);
-                    self.write_primitive_type(&val.primitive_type());
-                    self.write_primitive(val);
+                    self.write_u8(8)?;
+                    self.write_primitive_type(&val.primitive_type())?;
+                    self.write_primitive(val)
                 }
                 Member::Reference(id) => {
-                    self.write_u8(9);
-                    self.write_i32(*id);
-                    self.add_todo(*id);
+                    let rec = self.rec;
+                    if let Some(inlined) = rec.layout.inlined.get(&(parent, index)) {
+                        if inlined.last() == Some(id)
+                            && !self.written.contains(id)
+                            && rec.records.contains_key(id)
+                        {
+                            for &library_id in &inlined[..inlined.len() - 1] {
+                                if rec.records.contains_key(&library_id) {
+                                    self.write_record_once(library_id)?;
+                                }
+                            }
+                            return self.write_record_once(*id);
+                        }
+                    }
+                    self.write_u8(9)?;
+                    self.write_i32(*id)?;
+                    if !self.written.contains(id) {
+                        self.todo.push_back(*id);
+                    }
+                    Ok(())
                 }
                 Member::Null => self.write_u8(10),
                 Member::NullMultiple(count) => {
                     if *count < 0x100 {
-                        self.write_u8(13);
-                        self.write_u8(*count as u8);
+                        self.write_u8(13)?;
+                        self.write_u8(*count as u8)
                     } else {
-                        self.write_u8(14);
-                        self.write_i32(*count);
+                        self.write_u8(14)?;
+                        self.write_i32(*count)
                     }
                 }
             }
         }
     }
 
-    fn write_primitive_type(&mut self, typ: &PrimitiveType) {
+    fn write_primitive_type(&mut self, typ: &PrimitiveType) -> io::Result<()> {
         self.write_u8(match typ {
             PrimitiveType::Boolean => 1,
             PrimitiveType::Byte => 2,
@@ -218,51 +366,134 @@ impl Serializer {
             PrimitiveType::UInt64 => 16,
             PrimitiveType::Null => 17,
             PrimitiveType::String => 18,
-        });
+        })
     }
 
-    fn write_primitive(&mut self, val: &Primitive) {
+    fn write_primitive(&mut self, val: &Primitive) -> io::Result<()> {
         match val {
             Primitive::Boolean(val) => self.write_u8(*val as u8),
             Primitive::Byte(val) => self.write_u8(*val),
-            Primitive::Char(_val) => todo!(),
+            Primitive::Char(val) if (*val as u32) > 0xFFFF => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Char outside of the Basic Multilingual Plane: {:?}", val),
+            )),
+            Primitive::Char(val) => {
+                let mut buf = [0; 4];
+                self.output.write_all(val.encode_utf8(&mut buf).as_bytes())
+            }
             Primitive::Decimal(val) => self.write_string(val),
-            Primitive::Double(val) => self.output.write_f64::<LittleEndian>(*val).unwrap(),
-            Primitive::Int16(val) => self.output.write_i16::<LittleEndian>(*val).unwrap(),
-            Primitive::Int32(val) => self.output.write_i32::<LittleEndian>(*val).unwrap(),
-            Primitive::Int64(val) => self.output.write_i64::<LittleEndian>(*val).unwrap(),
-            Primitive::Int8(val) => self.output.write_i8(*val).unwrap(),
-            Primitive::Single(val) => self.output.write_f32::<LittleEndian>(*val).unwrap(),
-            Primitive::TimeSpan(val) => self.output.write_i64::<LittleEndian>(*val).unwrap(),
-            Primitive::DateTime(val) => self.output.write_i64::<LittleEndian>(*val).unwrap(),
-            Primitive::UInt16(val) => self.output.write_u16::<LittleEndian>(*val).unwrap(),
-            Primitive::UInt32(val) => self.output.write_u32::<LittleEndian>(*val).unwrap(),
-            Primitive::UInt64(val) => self.output.write_u64::<LittleEndian>(*val).unwrap(),
-            Primitive::Null => (),
+            Primitive::Double(val) => self.output.write_f64::<LittleEndian>(*val),
+            Primitive::Int16(val) => self.output.write_i16::<LittleEndian>(*val),
+            Primitive::Int32(val) => self.output.write_i32::<LittleEndian>(*val),
+            Primitive::Int64(val) => self.output.write_i64::<LittleEndian>(*val),
+            Primitive::Int8(val) => self.output.write_i8(*val),
+            Primitive::Single(val) => self.output.write_f32::<LittleEndian>(*val),
+            Primitive::TimeSpan(val) => self.output.write_i64::<LittleEndian>(*val),
+            Primitive::DateTime(val) => self.output.write_i64::<LittleEndian>(*val),
+            Primitive::UInt16(val) => self.output.write_u16::<LittleEndian>(*val),
+            Primitive::UInt32(val) => self.output.write_u32::<LittleEndian>(*val),
+            Primitive::UInt64(val) => self.output.write_u64::<LittleEndian>(*val),
+            Primitive::Null => Ok(()),
             Primitive::String(val) => self.write_string(val),
         }
     }
 
-    fn write_string(&mut self, val: &str) {
+    fn write_string(&mut self, val: &str) -> io::Result<()> {
         let mut length = val.len();
-        assert!(length <= 0x7FFF_FFFF);
+        if length > MAX_STRING_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("String of {} bytes is too long", length),
+            ));
+        }
         loop {
             let val = (length & 0b111_1111) as u8;
             length >>= 7;
             if length == 0 {
-                self.write_u8(val);
+                self.write_u8(val)?;
                 break;
             }
-            self.write_u8(val | 0b1000_0000);
+            self.write_u8(val | 0b1000_0000)?;
+        }
+        self.output.write_all(val.as_bytes())
+    }
+
+    fn write_u8(&mut self, i: u8) -> io::Result<()> {
+        self.output.write_u8(i)
+    }
+
+    fn write_i32(&mut self, i: i32) -> io::Result<()> {
+        self.output.write_i32::<LittleEndian>(i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::{char_array, sample_stream};
+    use crate::parser;
+    use crate::path::{Location, ValueMut};
+    use crate::records::*;
+
+    #[test]
+    fn byte_identical_round_trip() {
+        let bytes = sample_stream();
+        let rec = parser::parse(&bytes).unwrap();
+        assert_eq!(super::serialize(&rec), bytes);
+    }
+
+    #[test]
+    fn edit_keeps_layout() {
+        let mut bytes = sample_stream();
+        let mut rec = parser::parse(&bytes).unwrap();
+        match rec.get_mut(Location::Member(1, 3)) {
+            Some(ValueMut::Member(Member::Primitive(Primitive::Int32(count)))) => *count = 43,
+            _ => unreachable!(),
         }
-        self.output.extend(val.as_bytes());
+
+        // `count` comes after the records inlined into `name`, `items` and `other`
+        assert_eq!(bytes[196], 42);
+        bytes[196] = 43;
+        assert_eq!(super::serialize(&rec), bytes);
     }
 
-    fn write_u8(&mut self, i: u8) {
-        self.output.write_u8(i).unwrap();
+    #[test]
+    fn serialize_without_layout() {
+        let mut rec = parser::parse(&sample_stream()).unwrap();
+        rec.layout = Layout::default();
+        let bytes = super::serialize(&rec);
+        assert_eq!(super::serialize(&parser::parse(&bytes).unwrap()), bytes);
     }
 
-    fn write_i32(&mut self, i: i32) {
-        self.output.write_i32::<LittleEndian>(i).unwrap();
+    #[test]
+    fn char_round_trip() {
+        let chars = [
+            '\0', 'a', '\x7F', '\u{80}', 'é', '\u{7FF}', '\u{800}', '€', '\u{FFFD}', '\u{FFFF}',
+        ];
+        let encoded: String = chars.iter().collect();
+        let bytes = char_array(encoded.as_bytes(), chars.len() as u8);
+
+        let rec = parser::parse(&bytes).unwrap();
+        assert_eq!(super::serialize(&rec), bytes);
+    }
+
+    #[test]
+    fn char_outside_bmp() {
+        let mut rec = parser::parse(&char_array(b"a", 1)).unwrap();
+        match rec.records.get_mut(&1) {
+            Some(Record::PrimitiveArray(_, vals)) => vals[0] = Primitive::Char('\u{1F600}'),
+            _ => unreachable!(),
+        }
+        let err = super::serialize_into(&rec, Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn member_of_wrong_type() {
+        let mut rec = parser::parse(&sample_stream()).unwrap();
+        for member in &[Member::Null, Member::Primitive(Primitive::Int64(43))] {
+            rec.records.get_mut(&1).unwrap().as_class_mut().members[3] = member.clone();
+            let err = super::serialize_into(&rec, Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        }
     }
 }
@@ -0,0 +1,586 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use nrbf::*;
+
+use crate::catalog;
+
+pub struct UpgradeState {
+    pub id: String,
+    pub level: i32,
+    pub is_starting: bool,
+    pub flag: bool,
+}
+
+/// Why the upgrades of a save couldn't be read or changed.
+#[derive(Debug)]
+pub enum Error {
+    /// The save doesn't have the records and members of a Bad North save
+    Malformed(String),
+    /// The save has no upgrade entries, so the classes of new entries aren't known
+    NoEntryClass,
+    /// Classes and class upgrades can't be starting upgrades
+    CannotBeStarting(&'static catalog::Upgrade),
+    NotUnlocked(&'static catalog::Upgrade),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Malformed(message) => write!(f, "Unexpected save layout: {}", message),
+            Error::NoEntryClass => write!(
+                f,
+                "The save contains no upgrade entries to copy the class of new entries from"
+            ),
+            Error::CannotBeStarting(upgrade) => write!(
+                f,
+                "{} is a {} and can't be a starting upgrade",
+                upgrade.name, upgrade.category
+            ),
+            Error::NotUnlocked(upgrade) => {
+                write!(f, "{} is not unlocked in this save", upgrade.name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn malformed<T>(message: impl Into<String>) -> Result<T> {
+    Err(Error::Malformed(message.into()))
+}
+
+fn class(rec: &DeserializedRecord, id: i32) -> Result<&Class> {
+    match rec.records.get(&id) {
+        Some(Record::Class(class)) => Ok(class),
+        Some(_) => malformed(format!("record {} is not a class", id)),
+        None => malformed(format!("record {} is missing", id)),
+    }
+}
+
+/// The index and value of a member of a class.
+fn member<'a>(
+    rec: &'a DeserializedRecord,
+    class: &'a Class,
+    name: &str,
+) -> Result<(usize, &'a Member)> {
+    let class_type = rec.class_type(class);
+    let index = class_type.member_names.iter().position(|n| n == name);
+    match index.and_then(|i| Some((i, class.members.get(i)?))) {
+        Some(found) => Ok(found),
+        None => malformed(format!("{} has no member '{}'", class_type.name, name)),
+    }
+}
+
+fn reference(rec: &DeserializedRecord, class: &Class, name: &str) -> Result<i32> {
+    match member(rec, class, name)? {
+        (_, Member::Reference(id)) => Ok(*id),
+        _ => malformed(format!("'{}' is not a reference", name)),
+    }
+}
+
+fn string(rec: &DeserializedRecord, id: i32) -> Result<&str> {
+    match rec.records.get(&id) {
+        Some(Record::String(s)) => Ok(s),
+        _ => malformed(format!("record {} is not a string", id)),
+    }
+}
+
+fn boolean(member: &Member) -> Result<bool> {
+    match member {
+        Member::Primitive(Primitive::Boolean(val)) => Ok(*val),
+        _ => malformed("expected a Boolean"),
+    }
+}
+
+fn int32(member: &Member) -> Result<i32> {
+    match member {
+        Member::Primitive(Primitive::Int32(val)) => Ok(*val),
+        _ => malformed("expected an Int32"),
+    }
+}
+
+/// The id of the list of upgrade entries and the ids of the entries in it.
+fn upgrade_entries(rec: &DeserializedRecord) -> Result<(i32, Vec<i32>)> {
+    let user_save = class(rec, rec.root_id)?;
+    let inventory = class(rec, reference(rec, user_save, "inventory")?)?;
+    let upgrades_id = reference(rec, inventory, "upgrades")?;
+    let upgrades = match rec.list(upgrades_id) {
+        Some(upgrades) => upgrades,
+        None => return malformed("the upgrades are not a list"),
+    };
+    let entries = upgrades
+        .iter()
+        .map(|item| match item {
+            Value::Member(Member::Reference(id)) => Ok(*id),
+            _ => malformed("the upgrades are not a list of objects"),
+        })
+        .collect::<Result<_>>()?;
+    Ok((upgrades_id, entries))
+}
+
+/// The class type of the upgrade entries. It's looked up by the element type of `_items`,
+/// so it's also found when the list is empty.
+fn entry_class_type(rec: &DeserializedRecord, upgrades_id: i32) -> Result<usize> {
+    let items_id = rec.list(upgrades_id).map(|upgrades| upgrades.items_id());
+    let (name, library_id) = match items_id.and_then(|id| rec.records.get(&id)) {
+        Some(Record::BinaryArray(BinaryArray {
+            member_type: MemberType::Class(name, library_id),
+            ..
+        })) => (name, *library_id),
+        _ => return malformed("the upgrade list has no element class"),
+    };
+    class_type_id(rec, name, library_id)
+}
+
+fn class_type_id(rec: &DeserializedRecord, name: &str, library_id: i32) -> Result<usize> {
+    let id = rec
+        .class_types
+        .iter()
+        .position(|t| !t.system_class && t.name == name && t.library_id == library_id);
+    match id {
+        Some(id) => Ok(id),
+        None => malformed(format!("the save has no metadata for the class {}", name)),
+    }
+}
+
+/// The class types of new upgrade entries and of their upgrades. A save only contains the
+/// metadata of classes that have instances, so it's lost once all entries are removed.
+fn new_entry_class_types(rec: &DeserializedRecord, upgrades_id: i32) -> Result<(usize, usize)> {
+    let entry_type_id = entry_class_type(rec, upgrades_id).map_err(|_| Error::NoEntryClass)?;
+    let entry_type = &rec.class_types[entry_type_id];
+    let index = entry_type.member_names.iter().position(|n| n == "upgrade");
+    let (name, library_id) = match index.map(|i| &entry_type.member_types[i]) {
+        Some(MemberType::Class(name, library_id)) => (name, *library_id),
+        _ => return malformed("upgrade entries have no upgrade class"),
+    };
+    let upgrade_type_id = class_type_id(rec, name, library_id).map_err(|_| Error::NoEntryClass)?;
+    Ok((entry_type_id, upgrade_type_id))
+}
+
+/// An upgrade entry with the ids and member indices needed to change it.
+struct Entry<'a> {
+    id: i32,
+    is_starting_index: usize,
+    upgrade_id: i32,
+    level_index: usize,
+    name: &'a str,
+}
+
+fn entry(rec: &DeserializedRecord, id: i32) -> Result<Entry<'_>> {
+    let entry = class(rec, id)?;
+    let upgrade_id = reference(rec, entry, "upgrade")?;
+    let upgrade = class(rec, upgrade_id)?;
+    Ok(Entry {
+        id,
+        is_starting_index: member(rec, entry, "isStarting")?.0,
+        upgrade_id,
+        level_index: member(rec, upgrade, "level")?.0,
+        name: string(rec, reference(rec, upgrade, "name")?)?,
+    })
+}
+
+fn set_member(rec: &mut DeserializedRecord, id: i32, index: usize, val: Primitive) {
+    if let Some(Record::Class(class)) = rec.records.get_mut(&id) {
+        class.members[index] = Member::Primitive(val);
+    }
+}
+
+/// Read all upgrade entries of a save together with the name of their third boolean field.
+pub fn read_upgrades(rec: &DeserializedRecord) -> Result<(String, Vec<UpgradeState>)> {
+    let (upgrades_id, entries) = upgrade_entries(rec)?;
+
+    let entry_class_type = &rec.class_types[entry_class_type(rec, upgrades_id)?];
+    let flag_name = match entry_class_type.member_names.get(2) {
+        Some(name) => name.clone(),
+        None => return malformed("upgrade entries have no third member"),
+    };
+    let mut result = Vec::with_capacity(entries.len());
+
+    for entry_id in entries {
+        let found = entry(rec, entry_id)?;
+        let entry = class(rec, entry_id)?;
+        let upgrade = class(rec, found.upgrade_id)?;
+        let flag = match entry.members.get(2) {
+            Some(flag) => flag,
+            None => return malformed("upgrade entries have no third member"),
+        };
+        result.push(UpgradeState {
+            id: found.name.into(),
+            level: int32(&upgrade.members[found.level_index])?,
+            is_starting: boolean(&entry.members[found.is_starting_index])?,
+            flag: boolean(flag)?,
+        });
+    }
+
+    Ok((flag_name, result))
+}
+
+/// The ids of the upgrades that `unlock_upgrades` changed or came across.
+#[derive(Debug, Default)]
+pub struct Unlocked {
+    /// Upgrades the save already had, which got the new level
+    pub updated: Vec<String>,
+    /// Upgrades that got new entries
+    pub added: Vec<String>,
+    /// Upgrades in the save that aren't in the catalog
+    pub unknown: Vec<String>,
+}
+
+pub fn unlock_upgrades(
+    rec: &mut DeserializedRecord,
+    mut upgrades_to_add: HashSet<&str>,
+    level: i32,
+) -> Result<Unlocked> {
+    let mut unlocked = Unlocked::default();
+    let mut upgrade_entries_to_update = Vec::new();
+    let mut upgrade_inners_to_update = Vec::new();
+
+    let (upgrades_id, entries) = upgrade_entries(rec)?;
+
+    for entry_id in entries {
+        let entry = entry(rec, entry_id)?;
+        if catalog::by_id(entry.name).is_none() {
+            unlocked.unknown.push(entry.name.into());
+        }
+        if !upgrades_to_add.remove(entry.name) {
+            continue;
+        }
+        unlocked.updated.push(entry.name.into());
+        if can_be_starting(entry.name) {
+            upgrade_entries_to_update.push((entry.id, entry.is_starting_index));
+        }
+        upgrade_inners_to_update.push((entry.upgrade_id, entry.level_index));
+    }
+
+    for (id, is_starting_index) in upgrade_entries_to_update {
+        set_member(rec, id, is_starting_index, Primitive::Boolean(true));
+    }
+
+    for (id, level_index) in upgrade_inners_to_update {
+        set_member(rec, id, level_index, Primitive::Int32(level));
+    }
+
+    if upgrades_to_add.is_empty() {
+        return Ok(unlocked);
+    }
+
+    let (upgrade_entry_class_id, upgrade_inner_class_id) = new_entry_class_types(rec, upgrades_id)?;
+
+    let mut upgrade_entries_to_add = Vec::new();
+
+    for upgrade_name in upgrades_to_add {
+        unlocked.added.push(upgrade_name.into());
+        let is_starting = can_be_starting(upgrade_name);
+        let name_id = rec.add_record(Record::String(upgrade_name.into()));
+        let upgrade_id = rec.add_record(Record::Class(Class {
+            class_type_id: upgrade_inner_class_id,
+            members: vec![
+                Member::Reference(name_id),
+                Member::Primitive(Primitive::Int32(level)),
+            ],
+        }));
+        upgrade_entries_to_add.push(rec.add_record(Record::Class(Class {
+            class_type_id: upgrade_entry_class_id,
+            members: vec![
+                Member::Reference(upgrade_id),
+                Member::Primitive(Primitive::Boolean(is_starting)),
+                Member::Primitive(Primitive::Boolean(true)),
+            ],
+        })));
+    }
+
+    let mut upgrades = rec.list_mut(upgrades_id).unwrap();
+    for id in upgrade_entries_to_add {
+        if let Err(err) = upgrades.push(Member::Reference(id)) {
+            return malformed(err.to_string());
+        }
+    }
+    Ok(unlocked)
+}
+
+/// Whether unlocking an upgrade marks it as a starting upgrade.
+fn can_be_starting(id: &str) -> bool {
+    matches!(catalog::by_id(id), Some(u) if u.can_be_starting)
+}
+
+/// Remove the given upgrades from a save, or all of its upgrades if `upgrades_to_remove`
+/// is `None`. Returns the ids of the removed upgrades.
+pub fn remove_upgrades(
+    rec: &mut DeserializedRecord,
+    upgrades_to_remove: Option<HashSet<&str>>,
+) -> Result<Vec<String>> {
+    let (upgrades_id, entries) = upgrade_entries(rec)?;
+    let mut indices_to_remove = Vec::new();
+    let mut removed = Vec::new();
+
+    for (i, entry_id) in entries.into_iter().enumerate() {
+        let entry = entry(rec, entry_id)?;
+        let remove = match &upgrades_to_remove {
+            Some(names) => names.contains(entry.name),
+            None => true,
+        };
+        if remove {
+            removed.push(entry.name.into());
+            indices_to_remove.push(i);
+        }
+    }
+
+    if indices_to_remove.is_empty() {
+        return Ok(removed);
+    }
+
+    let mut upgrades = rec.list_mut(upgrades_id).unwrap();
+    for i in indices_to_remove.into_iter().rev() {
+        upgrades.remove(i);
+    }
+
+    rec.gc();
+    Ok(removed)
+}
+
+/// Change the level and starting flag of an upgrade in a save.
+pub fn set_upgrade(
+    rec: &mut DeserializedRecord,
+    upgrade: &'static catalog::Upgrade,
+    level: Option<i32>,
+    starting: Option<bool>,
+) -> Result<()> {
+    if starting == Some(true) && !upgrade.can_be_starting {
+        return Err(Error::CannotBeStarting(upgrade));
+    }
+
+    let (_, entries) = upgrade_entries(rec)?;
+    let mut found = None;
+
+    for entry_id in entries {
+        let entry = entry(rec, entry_id)?;
+        if entry.name == upgrade.id {
+            found = Some((
+                entry.id,
+                entry.is_starting_index,
+                entry.upgrade_id,
+                entry.level_index,
+            ));
+            break;
+        }
+    }
+
+    let (entry_id, is_starting_index, upgrade_id, level_index) = match found {
+        Some(found) => found,
+        None => return Err(Error::NotUnlocked(upgrade)),
+    };
+
+    if let Some(starting) = starting {
+        set_member(
+            rec,
+            entry_id,
+            is_starting_index,
+            Primitive::Boolean(starting),
+        );
+    }
+
+    if let Some(level) = level {
+        set_member(rec, upgrade_id, level_index, Primitive::Int32(level));
+    }
+
+    Ok(())
+}
+
+pub fn print_upgrades_table(
+    flag_name: &str,
+    upgrades: &[UpgradeState],
+    missing: &[&catalog::Upgrade],
+) {
+    println!(
+        "{:<32} {:<20} {:>5} {:>10} {:>10}",
+        "ID", "Name", "Level", "isStarting", flag_name
+    );
+    for upgrade in upgrades {
+        println!(
+            "{:<32} {:<20} {:>5} {:>10} {:>10}",
+            upgrade.id,
+            catalog::by_id(&upgrade.id).map_or("?", |u| u.name),
+            upgrade.level,
+            upgrade.is_starting,
+            upgrade.flag
+        );
+    }
+
+    if !missing.is_empty() {
+        println!();
+        println!("Missing upgrades:");
+        for upgrade in missing {
+            println!(
+                "{:<32} {:<20} {}",
+                upgrade.id, upgrade.name, upgrade.category
+            );
+        }
+    }
+}
+
+pub fn print_upgrades_json(
+    flag_name: &str,
+    upgrades: &[UpgradeState],
+    missing: &[&catalog::Upgrade],
+) {
+    let upgrades: Vec<_> = upgrades
+        .iter()
+        .map(|upgrade| {
+            let mut obj = serde_json::Map::new();
+            obj.insert("id".into(), upgrade.id.clone().into());
+            obj.insert(
+                "name".into(),
+                catalog::by_id(&upgrade.id).map(|u| u.name).into(),
+            );
+            obj.insert("level".into(), upgrade.level.into());
+            obj.insert("isStarting".into(), upgrade.is_starting.into());
+            obj.insert(flag_name.into(), upgrade.flag.into());
+            serde_json::Value::Object(obj)
+        })
+        .collect();
+    let missing: Vec<_> = missing
+        .iter()
+        .map(|upgrade| {
+            serde_json::json!({
+                "id": upgrade.id,
+                "name": upgrade.name,
+                "category": upgrade.category.to_string(),
+            })
+        })
+        .collect();
+
+    let output = serde_json::json!({
+        "upgrades": upgrades,
+        "missing": missing,
+    });
+    println!("{}", serde_json::to_string_pretty(&output).unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn save() -> DeserializedRecord {
+        nrbf::parse(include_bytes!("../tests/saves/synthetic.dat")).unwrap()
+    }
+
+    fn upgrades(rec: &DeserializedRecord) -> Vec<(String, i32, bool)> {
+        let (_, upgrades) = read_upgrades(rec).unwrap();
+        upgrades
+            .into_iter()
+            .map(|u| (u.id, u.level, u.is_starting))
+            .collect()
+    }
+
+    #[test]
+    fn unlock() {
+        let mut rec = save();
+        let names = ["Hero_Class_Infantry", "Hero_Upgrade_Bomb"];
+        let unlocked = unlock_upgrades(&mut rec, names.iter().copied().collect(), 1).unwrap();
+        assert_eq!(unlocked.updated, ["Hero_Class_Infantry"]);
+        assert_eq!(unlocked.added, ["Hero_Upgrade_Bomb"]);
+        assert!(unlocked.unknown.is_empty());
+        let rec = nrbf::parse(&nrbf::serialize(&rec)).unwrap();
+        assert_eq!(
+            upgrades(&rec),
+            vec![
+                ("Hero_Class_Infantry".into(), 1, false),
+                ("Hero_Trait_Giant".into(), 2, true),
+                ("Hero_Upgrade_Bomb".into(), 1, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn flag_name_of_empty_list() {
+        let mut rec = save();
+        let (upgrades_id, _) = upgrade_entries(&rec).unwrap();
+        rec.list_mut(upgrades_id).unwrap().truncate(0);
+        let (flag_name, upgrades) = read_upgrades(&rec).unwrap();
+        assert_eq!(flag_name, "isUnlocked");
+        assert!(upgrades.is_empty());
+    }
+
+    #[test]
+    fn remove() {
+        let mut rec = save();
+        let names = ["Hero_Class_Infantry"].iter().copied().collect();
+        let removed = remove_upgrades(&mut rec, Some(names)).unwrap();
+        assert_eq!(removed, ["Hero_Class_Infantry"]);
+        assert_eq!(upgrades(&rec), vec![("Hero_Trait_Giant".into(), 2, true)]);
+    }
+
+    #[test]
+    fn remove_all_then_unlock() {
+        let mut rec = save();
+        let (upgrades_id, entries) = upgrade_entries(&rec).unwrap();
+        let name_id = reference(
+            &rec,
+            class(&rec, entry(&rec, entries[0]).unwrap().upgrade_id).unwrap(),
+            "name",
+        )
+        .unwrap();
+        *rec.records.get_mut(&name_id).unwrap() = Record::String("Hero_Trait_Unknown".into());
+        let removed = remove_upgrades(&mut rec, None).unwrap();
+        assert_eq!(removed, ["Hero_Trait_Unknown", "Hero_Trait_Giant"]);
+        assert!(upgrades(&rec).is_empty());
+        assert!(rec.list(upgrades_id).unwrap().is_empty());
+
+        let written = nrbf::parse(&nrbf::serialize(&rec)).unwrap();
+        let names = ["Hero_Upgrade_Bomb"].iter().copied().collect();
+        let unlocked = unlock_upgrades(&mut rec, names, 2).unwrap();
+        assert_eq!(unlocked.added, ["Hero_Upgrade_Bomb"]);
+        let rec = nrbf::parse(&nrbf::serialize(&rec)).unwrap();
+        assert_eq!(upgrades(&rec), vec![("Hero_Upgrade_Bomb".into(), 2, true)]);
+
+        // The written save has no entries left to take the classes from
+        let mut rec = written;
+        let names = ["Hero_Upgrade_Bomb"].iter().copied().collect();
+        assert!(matches!(
+            unlock_upgrades(&mut rec, names, 2),
+            Err(Error::NoEntryClass)
+        ));
+    }
+
+    #[test]
+    fn set() {
+        let mut rec = save();
+        let giant = catalog::by_id("Hero_Trait_Giant").unwrap();
+        set_upgrade(&mut rec, giant, Some(1), Some(false)).unwrap();
+        assert_eq!(upgrades(&rec)[1], ("Hero_Trait_Giant".into(), 1, false));
+
+        let bomb = catalog::by_id("Hero_Upgrade_Bomb").unwrap();
+        assert!(matches!(
+            set_upgrade(&mut rec, bomb, Some(1), None),
+            Err(Error::NotUnlocked(_))
+        ));
+    }
+
+    #[test]
+    fn set_class_as_starting() {
+        let mut rec = save();
+        let infantry = catalog::by_id("Hero_Class_Infantry").unwrap();
+        assert!(matches!(
+            set_upgrade(&mut rec, infantry, None, Some(true)),
+            Err(Error::CannotBeStarting(_))
+        ));
+        assert_eq!(upgrades(&rec)[0], ("Hero_Class_Infantry".into(), 1, false));
+        set_upgrade(&mut rec, infantry, None, Some(false)).unwrap();
+    }
+
+    #[test]
+    fn malformed_save() {
+        let mut rec = save();
+        let root = rec.root_id;
+        rec.records.get_mut(&root).unwrap().as_class_mut().members[0] = Member::Null;
+        let names = ["Hero_Upgrade_Bomb"].iter().copied().collect();
+        assert!(matches!(
+            unlock_upgrades(&mut rec, names, 2),
+            Err(Error::Malformed(_))
+        ));
+        assert!(read_upgrades(&rec).is_err());
+    }
+}
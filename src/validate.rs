@@ -0,0 +1,457 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::records::*;
+use crate::serializer::MAX_STRING_LENGTH;
+
+/// A problem that would make a document unserializable or unreadable by .NET.
+#[derive(Debug, Clone)]
+pub enum ValidationError {
+    MissingRoot {
+        root_id: i32,
+    },
+    UnknownClassType {
+        id: i32,
+        class_type_id: usize,
+    },
+    MemberCount {
+        id: i32,
+        expected: usize,
+        found: usize,
+    },
+    /// `member` is the member name for classes or the index for arrays
+    WrongMemberType {
+        id: i32,
+        member: String,
+        expected: MemberType,
+        found: Member,
+    },
+    DanglingReference {
+        id: i32,
+        member: String,
+        target: i32,
+    },
+    WrongPrimitiveType {
+        id: i32,
+        index: usize,
+        expected: PrimitiveType,
+        found: PrimitiveType,
+    },
+    ArrayLength {
+        id: i32,
+        expected: usize,
+        found: usize,
+    },
+    MissingLibrary {
+        class: String,
+        library_id: i32,
+    },
+    /// A char outside of the Basic Multilingual Plane, which doesn't fit a .NET `Char`
+    InvalidChar {
+        id: i32,
+        member: String,
+        value: char,
+    },
+    /// A string in the record, or in the metadata of its class, longer than the format allows
+    StringTooLong {
+        id: i32,
+        length: usize,
+    },
+    /// A run of nulls in a class, over elements that can't be null or of a non-positive length
+    InvalidNullRun {
+        id: i32,
+        member: String,
+        count: i32,
+    },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::MissingRoot { root_id } => {
+                write!(f, "The root record {} doesn't exist", root_id)
+            }
+            ValidationError::UnknownClassType { id, class_type_id } => {
+                write!(f, "Record {} has unknown class type {}", id, class_type_id)
+            }
+            ValidationError::MemberCount {
+                id,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Record {} has {} members but its class has {}",
+                id, found, expected
+            ),
+            ValidationError::WrongMemberType {
+                id,
+                member,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Member {} of record {} is {:?}, which doesn't fit {:?}",
+                member, id, found, expected
+            ),
+            ValidationError::DanglingReference { id, member, target } => write!(
+                f,
+                "Member {} of record {} refers to missing record {}",
+                member, id, target
+            ),
+            ValidationError::WrongPrimitiveType {
+                id,
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Element {} of the {:?} array {} is a {:?}",
+                index, expected, id, found
+            ),
+            ValidationError::ArrayLength {
+                id,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Array {} has {} values but its lengths call for {}",
+                id, found, expected
+            ),
+            ValidationError::MissingLibrary { class, library_id } => write!(
+                f,
+                "Class {} refers to missing library {}",
+                class, library_id
+            ),
+            ValidationError::InvalidChar { id, member, value } => write!(
+                f,
+                "Member {} of record {} is {:?}, which is outside of the Basic Multilingual Plane",
+                member, id, value
+            ),
+            ValidationError::StringTooLong { id, length } => write!(
+                f,
+                "Record {} has a string of {} bytes, more than the {} the format allows",
+                id, length, MAX_STRING_LENGTH
+            ),
+            ValidationError::InvalidNullRun { id, member, count } => write!(
+                f,
+                "Member {} of record {} is a run of {} nulls, which only fits arrays of nullable elements",
+                member, id, count
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl DeserializedRecord {
+    /// Check that the document can be serialized and read back by .NET.
+    /// Returns all problems of the records ordered by record id, followed by missing libraries.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
+        if !self.records.contains_key(&self.root_id) {
+            errors.push(ValidationError::MissingRoot {
+                root_id: self.root_id,
+            });
+        }
+
+        let mut ids: Vec<i32> = self.records.keys().copied().collect();
+        ids.sort_unstable();
+        let mut class_types = BTreeSet::new();
+        let mut libraries = Vec::new();
+
+        for id in ids {
+            match &self.records[&id] {
+                Record::Class(class) => {
+                    let class_type = match self.class_types.get(class.class_type_id) {
+                        Some(class_type) => class_type,
+                        None => {
+                            errors.push(ValidationError::UnknownClassType {
+                                id,
+                                class_type_id: class.class_type_id,
+                            });
+                            continue;
+                        }
+                    };
+                    if class_types.insert(class.class_type_id) {
+                        check_string(id, &class_type.name, &mut errors);
+                        for name in &class_type.member_names {
+                            check_string(id, name, &mut errors);
+                        }
+                        for typ in &class_type.member_types {
+                            check_type_name(id, typ, &mut errors);
+                        }
+                    }
+                    if class.members.len() != class_type.member_names.len() {
+                        errors.push(ValidationError::MemberCount {
+                            id,
+                            expected: class_type.member_names.len(),
+                            found: class.members.len(),
+                        });
+                    }
+                    let members = class.members.iter().zip(&class_type.member_names);
+                    for ((member, name), typ) in members.zip(&class_type.member_types) {
+                        let name = || format!("'{}'", name);
+                        if let Member::NullMultiple(count) = member {
+                            errors.push(ValidationError::InvalidNullRun {
+                                id,
+                                member: name(),
+                                count: *count,
+                            });
+                            continue;
+                        }
+                        self.check_member(id, name, member, typ, &mut errors);
+                    }
+                }
+                Record::ObjectArray(vals) => {
+                    for (i, val) in vals.iter().enumerate() {
+                        let typ = &MemberType::Object;
+                        self.check_member(id, || format!("[{}]", i), val, typ, &mut errors);
+                    }
+                }
+                Record::StringArray(vals) => {
+                    for (i, val) in vals.iter().enumerate() {
+                        let typ = &MemberType::String;
+                        self.check_member(id, || format!("[{}]", i), val, typ, &mut errors);
+                    }
+                }
+                Record::BinaryArray(array) => {
+                    let expected = array.lengths.iter().map(|&l| l.max(0) as usize).product();
+                    let found = array
+                        .values
+                        .iter()
+                        .map(|val| match val {
+                            Member::NullMultiple(count) => (*count).max(0) as usize,
+                            _ => 1,
+                        })
+                        .sum();
+                    if found != expected {
+                        errors.push(ValidationError::ArrayLength {
+                            id,
+                            expected,
+                            found,
+                        });
+                    }
+                    if let Some(library_id) = array.member_type.library_id() {
+                        libraries.push((id.to_string(), library_id));
+                    }
+                    check_type_name(id, &array.member_type, &mut errors);
+                    for (i, val) in array.values.iter().enumerate() {
+                        let typ = &array.member_type;
+                        self.check_member(id, || format!("[{}]", i), val, typ, &mut errors);
+                    }
+                }
+                Record::PrimitiveArray(typ, vals) => {
+                    for (index, val) in vals.iter().enumerate() {
+                        if val.primitive_type() != *typ {
+                            errors.push(ValidationError::WrongPrimitiveType {
+                                id,
+                                index,
+                                expected: typ.clone(),
+                                found: val.primitive_type(),
+                            });
+                        }
+                        check_primitive(id, || format!("[{}]", index), val, &mut errors);
+                    }
+                }
+                Record::BinaryLibrary(s) | Record::String(s) => check_string(id, s, &mut errors),
+            }
+        }
+
+        for class_type in class_types.into_iter().map(|i| &self.class_types[i]) {
+            if !class_type.system_class {
+                libraries.push((class_type.name.clone(), class_type.library_id));
+            }
+            for typ in &class_type.member_types {
+                if let Some(library_id) = typ.library_id() {
+                    libraries.push((class_type.name.clone(), library_id));
+                }
+            }
+        }
+        for (class, library_id) in libraries {
+            if !matches!(
+                self.records.get(&library_id),
+                Some(Record::BinaryLibrary(_))
+            ) {
+                errors.push(ValidationError::MissingLibrary { class, library_id });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn check_member(
+        &self,
+        id: i32,
+        name: impl Fn() -> String,
+        member: &Member,
+        typ: &MemberType,
+        errors: &mut Vec<ValidationError>,
+    ) {
+        if let Member::Primitive(val) = member {
+            check_primitive(id, &name, val, errors);
+        }
+        if let Member::NullMultiple(count) = member {
+            if *count <= 0 || matches!(typ, MemberType::Primitive(_)) {
+                errors.push(ValidationError::InvalidNullRun {
+                    id,
+                    member: name(),
+                    count: *count,
+                });
+                return;
+            }
+        }
+        if let Member::Reference(target) = member {
+            if !self.records.contains_key(target) {
+                errors.push(ValidationError::DanglingReference {
+                    id,
+                    member: name(),
+                    target: *target,
+                });
+                return;
+            }
+        }
+
+        let fits = match (typ, member) {
+            (MemberType::Primitive(typ), Member::Primitive(val)) => val.primitive_type() == *typ,
+            (MemberType::Primitive(_), _) => false,
+            (MemberType::Object, _) => true,
+            (_, Member::Primitive(_)) => false,
+            (MemberType::String, Member::Reference(target)) => {
+                matches!(self.records[target], Record::String(_))
+            }
+            _ => true,
+        };
+        if !fits {
+            errors.push(ValidationError::WrongMemberType {
+                id,
+                member: name(),
+                expected: typ.clone(),
+                found: member.clone(),
+            });
+        }
+    }
+}
+
+/// Chars and strings the serializer would reject.
+fn check_primitive(
+    id: i32,
+    name: impl Fn() -> String,
+    val: &Primitive,
+    errors: &mut Vec<ValidationError>,
+) {
+    match val {
+        Primitive::Char(value) if (*value as u32) > 0xFFFF => {
+            errors.push(ValidationError::InvalidChar {
+                id,
+                member: name(),
+                value: *value,
+            })
+        }
+        Primitive::String(s) | Primitive::Decimal(s) => check_string(id, s, errors),
+        _ => (),
+    }
+}
+
+fn check_type_name(id: i32, typ: &MemberType, errors: &mut Vec<ValidationError>) {
+    if let MemberType::SystemClass(name) | MemberType::Class(name, _) = typ {
+        check_string(id, name, errors);
+    }
+}
+
+fn check_string(id: i32, s: &str, errors: &mut Vec<ValidationError>) {
+    if s.len() > MAX_STRING_LENGTH {
+        errors.push(ValidationError::StringTooLong {
+            id,
+            length: s.len(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{int, save};
+
+    #[test]
+    fn reports_every_problem() {
+        let mut rec = save(1);
+        assert!(rec.validate().is_ok());
+
+        rec.root_id = 9;
+        rec.records.remove(&1);
+        if let Some(Record::Class(class)) = rec.records.get_mut(&2) {
+            class.members[0] = Member::Reference(4);
+            class.members[1] = Member::Reference(8);
+            class.members[2] = Member::Primitive(Primitive::Int64(5));
+            class.members.push(Member::Null);
+        }
+        if let Some(Record::PrimitiveArray(_, vals)) = rec.records.get_mut(&4) {
+            vals.push(Primitive::Boolean(true));
+            vals.push(Primitive::Char('\u{1F600}'));
+        }
+
+        let errors: Vec<String> = rec
+            .validate()
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "The root record 9 doesn't exist",
+                "Record 2 has 6 members but its class has 5",
+                "Member 'name' of record 2 is Reference(4), which doesn't fit String",
+                "Member 'values' of record 2 refers to missing record 8",
+                "Member 'count' of record 2 is Primitive(Int64(5)), which doesn't fit Primitive(Int32)",
+                "Element 1 of the Int32 array 4 is a Boolean",
+                "Element 2 of the Int32 array 4 is a Char",
+                "Member [2] of record 4 is '😀', which is outside of the Basic Multilingual Plane",
+                "Class Save refers to missing library 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_null_runs() {
+        let mut rec = save(1);
+        if let Some(Record::Class(class)) = rec.records.get_mut(&2) {
+            class.members[0] = Member::NullMultiple(1);
+        }
+        let int32 = MemberType::Primitive(PrimitiveType::Int32);
+        let array = |member_type: MemberType, values| {
+            Record::BinaryArray(BinaryArray {
+                array_type: BinaryArrayType::Single,
+                lengths: vec![3],
+                lower_bounds: vec![],
+                member_type,
+                values,
+            })
+        };
+        let values = vec![Member::Null, Member::NullMultiple(2)];
+        rec.records.insert(5, array(MemberType::String, values));
+        let values = vec![Member::NullMultiple(2), int(1)];
+        rec.records.insert(6, array(int32, values));
+        rec.records
+            .insert(7, Record::ObjectArray(vec![Member::NullMultiple(0)]));
+
+        let errors: Vec<String> = rec
+            .validate()
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "Member 'name' of record 2 is a run of 1 nulls, which only fits arrays of nullable elements",
+                "Member [0] of record 6 is a run of 2 nulls, which only fits arrays of nullable elements",
+                "Member [0] of record 7 is a run of 0 nulls, which only fits arrays of nullable elements",
+            ]
+        );
+    }
+}
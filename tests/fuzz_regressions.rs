@@ -0,0 +1,77 @@
+//! Inputs that broke the fuzz targets in `fuzz/`, minimized.
+//! Run the targets with `cargo +nightly fuzz run parse` and `cargo +nightly fuzz run round_trip`.
+
+use std::collections::BTreeSet;
+
+use nrbf::{DeserializedRecord, ParseError};
+
+/// What the `round_trip` target checks
+fn round_trip(bytes: &[u8]) {
+    let rec = nrbf::parse(bytes).unwrap();
+    let reparsed = nrbf::parse(&nrbf::serialize(&rec)).unwrap();
+    assert_eq!(ids(&reparsed), ids(&rec));
+    assert!(reparsed.structurally_eq(&rec));
+}
+
+/// The root, header and record ids, which the serializer keeps
+fn ids(rec: &DeserializedRecord) -> (i32, i32, BTreeSet<i32>) {
+    (
+        rec.root_id,
+        rec.header_id,
+        rec.records.keys().copied().collect(),
+    )
+}
+
+#[test]
+fn missing_root() {
+    round_trip(&[
+        0, 0xFF, 0xFF, 0xFD, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 1, 0, 0, 0, 0, 0, 0, 0, 11,
+    ]);
+}
+
+#[test]
+fn dangling_reference() {
+    round_trip(&[
+        0, 1, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 1, 0, 0, 0, 0, 0, 0, 0,
+        // An object array with a reference to record 5
+        16, 1, 0, 0, 0, 1, 0, 0, 0, 9, 5, 0, 0, 0, 11,
+    ]);
+}
+
+#[test]
+fn class_library_is_not_a_library() {
+    round_trip(&[
+        0, 1, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 1, 0, 0, 0, 0, 0, 0, 0,
+        // An object array holding
+        16, 1, 0, 0, 0, 1, 0, 0, 0, // a class C { a: String } from "library" 2,
+        5, 3, 0, 0, 0, 1, b'C', 1, 0, 0, 0, 1, b'a', 1, 2, 0, 0, 0,
+        // where 2 is the string in a
+        6, 2, 0, 0, 0, 1, b's', 11,
+    ]);
+}
+
+#[test]
+fn library_in_front_of_null() {
+    round_trip(&[
+        0, 1, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 1, 0, 0, 0, 0, 0, 0, 0,
+        // An object array holding a null with a library in front of it
+        16, 1, 0, 0, 0, 1, 0, 0, 0, 12, 2, 0, 0, 0, 1, b'L', 10, 11,
+    ]);
+}
+
+#[test]
+fn empty_null_run() {
+    // Used to make the serializer loop forever
+    let bytes = [
+        0, 1, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 1, 0, 0, 0, 0, 0, 0, 0,
+        // An object array holding a run of 0 nulls
+        16, 1, 0, 0, 0, 1, 0, 0, 0, 13, 0, 10, 11,
+    ];
+    match nrbf::parse(&bytes) {
+        Err(ParseError::InvalidLength {
+            offset: 26,
+            length: 0,
+        }) => (),
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
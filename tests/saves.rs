@@ -0,0 +1,57 @@
+//! Round trips over the save files in `tests/saves`.
+//!
+//! `synthetic.dat` is written byte by byte, not by this crate, in the shape of a Bad North
+//! save: a `UserSave` with an `Inventory` holding a `List<UpgradeEntry>`. Saves written by
+//! the game itself can be dropped into the same directory and are picked up automatically.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::path::PathBuf;
+
+fn saves() -> Vec<(PathBuf, Vec<u8>)> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/saves");
+    let mut saves: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(OsStr::new("dat")))
+        .map(|path| {
+            let bytes = fs::read(&path).unwrap();
+            (path, bytes)
+        })
+        .collect();
+    saves.sort();
+    assert!(!saves.is_empty());
+    saves
+}
+
+#[test]
+fn byte_identical() {
+    for (path, bytes) in saves() {
+        let rec = nrbf::parse(&bytes).unwrap();
+        assert!(rec.validate().is_ok(), "{}", path.display());
+        assert!(nrbf::serialize(&rec) == bytes, "{}", path.display());
+
+        let streamed = nrbf::parse_from_reader(&bytes[..]).unwrap();
+        assert!(nrbf::serialize(&streamed) == bytes, "{}", path.display());
+    }
+}
+
+#[test]
+fn json_round_trip() {
+    for (path, bytes) in saves() {
+        let rec = nrbf::parse(&bytes).unwrap();
+        let text = serde_json::to_string(&nrbf::to_json(&rec)).unwrap();
+        let imported = nrbf::from_json(&serde_json::from_str(&text).unwrap()).unwrap();
+        assert!(nrbf::serialize(&imported) == bytes, "{}", path.display());
+    }
+}
+
+#[test]
+fn without_layout() {
+    for (path, bytes) in saves() {
+        let mut rec = nrbf::parse(&bytes).unwrap();
+        rec.layout = nrbf::Layout::default();
+        let reparsed = nrbf::parse(&nrbf::serialize(&rec)).unwrap();
+        assert!(reparsed.structurally_eq(&rec), "{}", path.display());
+    }
+}